@@ -1,10 +1,98 @@
 use push_the_elephant;
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches, SubCommand};
 use std::time::Duration;
 extern crate log4rs;
 use log;
 
+/// Returns a subcommand acting on the outbox tables of the specified workers
+fn outbox_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("yaml_file")
+             .short("-y")
+             .long("yaml-file")
+             .value_name("YAML_FILE")
+             .help("YAML file with the workers configuration")
+             .takes_value(true))
+        .arg(Arg::with_name("pg_url")
+             .short("-p")
+             .long("pgurl")
+             .value_name("PG_URL")
+             .help("PostGreSQL URL (default: postgres://postgres@localhost:5433)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+        .arg(Arg::with_name("table_name")
+             .short("-t")
+             .long("table-name")
+             .value_name("TABLE_NAME")
+             .help("PostGreSQL Table name (default: events)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+        .arg(Arg::with_name("column_name")
+             .short("-c")
+             .long("column-name")
+             .value_name("COLUMN_NAME")
+             .help("PostGreSQL Table column name (default: payload)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+        .arg(Arg::with_name("key_column_name")
+             .short("-K")
+             .long("key-column-name")
+             .value_name("KEY_COLUMN_NAME")
+             .help("PostGreSQL Table column name of the message key (default: key)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+        .arg(Arg::with_name("headers_column_name")
+             .short("-H")
+             .long("headers-column-name")
+             .value_name("HEADERS_COLUMN_NAME")
+             .help("PostGreSQL Table JSON column name of the message headers (default: headers)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+        .arg(Arg::with_name("channel")
+             .short("-z")
+             .long("channel-name")
+             .value_name("CHANNEL_NAME")
+             .help("PostGreSQL channel name (default: events.activity)")
+             .takes_value(true)
+             .conflicts_with("yaml_file"))
+}
+
+/// Applies the specified action to the outbox table of every worker described by the subcommand arguments
+fn for_each_outbox(matches: &ArgMatches, action: fn(&push_the_elephant::Worker) -> Result<i32, Box<std::error::Error>>) {
+    if let Some(yaml_file) = matches.value_of("yaml_file") {
+        let configurations = push_the_elephant::PushTheElephantConfiguration::create_from_yaml_filename(yaml_file)
+            .expect("Could not read configuration!");
+        for c in configurations.iter() {
+            let worker = push_the_elephant::WorkerBuilder::from_configuration(c).build().unwrap();
+            let version = action(&worker).expect("Could not update schema!");
+            println!("{:?}: schema version {}", c.table_name.as_ref().map_or("events", |x| x.as_str()), version);
+        }
+        return;
+    }
+    let mut builder = push_the_elephant::WorkerBuilder::default();
+    let table_name = matches.value_of("table_name").unwrap_or("events");
+    builder.table_name(table_name);
+    if let Some(pgurl) = matches.value_of("pg_url") {
+        builder.pgurl(pgurl);
+    }
+    if let Some(column_name) = matches.value_of("column_name") {
+        builder.column_name(column_name);
+    }
+    if let Some(key_column_name) = matches.value_of("key_column_name") {
+        builder.key_column_name(key_column_name);
+    }
+    if let Some(headers_column_name) = matches.value_of("headers_column_name") {
+        builder.headers_column_name(headers_column_name);
+    }
+    if let Some(channel) = matches.value_of("channel") {
+        builder.channel(channel);
+    }
+    let version = action(&builder.build().unwrap()).expect("Could not update schema!");
+    println!("{:?}: schema version {}", table_name, version);
+}
+
 fn main() {
     let matches = App::new("Push the Elephant")
                           .version("0.0.1")
@@ -55,6 +143,42 @@ fn main() {
                                .help("PostGreSQL Table column name (default: payload)")
                                .takes_value(true)
                                .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("payload_mode")
+                               .long("payload-mode")
+                               .value_name("PAYLOAD_MODE")
+                               .help("Payload source, either column or row, i.e. the whole row as JSON (default: column)")
+                               .possible_values(&["column", "row"])
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("payload_columns")
+                               .long("payload-columns")
+                               .value_name("PAYLOAD_COLUMNS")
+                               .help("Comma separated columns of the row payload (default: all the columns)")
+                               .requires("payload_mode")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("key_column_name")
+                               .short("-K")
+                               .long("key-column-name")
+                               .value_name("KEY_COLUMN_NAME")
+                               .help("PostGreSQL Table column name of the message key (default: none)")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("headers_column_name")
+                               .short("-H")
+                               .long("headers-column-name")
+                               .value_name("HEADERS_COLUMN_NAME")
+                               .help("PostGreSQL Table JSON column name of the message headers (default: none)")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("format")
+                               .short("-f")
+                               .long("format")
+                               .value_name("FORMAT")
+                               .help("PostGreSQL Table layout, either plain or debezium (default: plain)")
+                               .possible_values(&["plain", "debezium"])
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
                           .arg(Arg::with_name("channel")
                                .short("-z")
                                .long("channel-name")
@@ -90,18 +214,173 @@ fn main() {
                                .help("Timeout after which rows are processed using a standard query (ms, default: 60000)")
                                .takes_value(true)
                                .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("schema_registry_url")
+                               .short("-r")
+                               .long("schema-registry-url")
+                               .value_name("SCHEMA_REGISTRY_URL")
+                               .help("Schema registry URL, enables Avro encoding of JSON payloads (default: none)")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("avro_schema_file")
+                               .short("-a")
+                               .long("avro-schema-file")
+                               .value_name("AVRO_SCHEMA_FILE")
+                               .help("Avro schema registered for the topics (default: the latest registered schema)")
+                               .takes_value(true)
+                               .requires("schema_registry_url")
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("protobuf_descriptor_set")
+                               .short("-d")
+                               .long("protobuf-descriptor-set")
+                               .value_name("PROTOBUF_DESCRIPTOR_SET")
+                               .help("Protobuf descriptor set file, enables protobuf encoding of JSON payloads (default: none)")
+                               .takes_value(true)
+                               .requires("protobuf_message")
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("protobuf_message")
+                               .short("-m")
+                               .long("protobuf-message")
+                               .value_name("PROTOBUF_MESSAGE")
+                               .help("Fully qualified name of the protobuf message in the descriptor set")
+                               .takes_value(true)
+                               .requires("protobuf_descriptor_set")
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("dead_letter_topic")
+                               .short("-D")
+                               .long("dead-letter-topic")
+                               .value_name("DEAD_LETTER_TOPIC")
+                               .help("Kafka topic where messages that can not be encoded are sent (default: none, i.e. fail)")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("quarantine_table")
+                               .long("quarantine-table")
+                               .value_name("QUARANTINE_TABLE")
+                               .help("PostGreSQL Table where messages that can not be sent are stored, instead of the dead letter topic (default: none)")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("json_schema")
+                               .long("json-schema")
+                               .value_name("TOPIC=JSON_SCHEMA_FILE")
+                               .help("JSON Schema file validating the payloads of a topic, can be repeated (default: none)")
+                               .takes_value(true)
+                               .multiple(true)
+                               .number_of_values(1)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("cloud_events_mode")
+                               .long("cloud-events-mode")
+                               .value_name("CLOUD_EVENTS_MODE")
+                               .help("Wraps messages as CloudEvents, either structured or binary (default: none)")
+                               .possible_values(&["structured", "binary"])
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("cloud_events_id_column")
+                               .long("cloud-events-id-column")
+                               .value_name("CLOUD_EVENTS_ID_COLUMN")
+                               .help("PostGreSQL Table column name of the CloudEvents id (default: id)")
+                               .requires("cloud_events_mode")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("cloud_events_source_column")
+                               .long("cloud-events-source-column")
+                               .value_name("CLOUD_EVENTS_SOURCE_COLUMN")
+                               .help("PostGreSQL Table column name of the CloudEvents source (default: none, i.e. the table name)")
+                               .requires("cloud_events_mode")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("cloud_events_type_column")
+                               .long("cloud-events-type-column")
+                               .value_name("CLOUD_EVENTS_TYPE_COLUMN")
+                               .help("PostGreSQL Table column name of the CloudEvents type (default: none, i.e. push_the_elephant.event)")
+                               .requires("cloud_events_mode")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
+                          .arg(Arg::with_name("cloud_events_time_column")
+                               .long("cloud-events-time-column")
+                               .value_name("CLOUD_EVENTS_TIME_COLUMN")
+                               .help("PostGreSQL Table column name of the CloudEvents time (default: none, i.e. no time)")
+                               .requires("cloud_events_mode")
+                               .takes_value(true)
+                               .conflicts_with("yaml_file"))
                           .arg(Arg::with_name("log4rs_file")
                                .short("-l")
                                .long("log4rs-configuration")
                                .value_name("LOG4RS_CONFIGURATION")
                                .help("Log4rs YAML configuration file")
                                .takes_value(true))
+                          .subcommand(SubCommand::with_name("cdc")
+                               .about("Generates change data capture triggers that copy the changes of a table into the events table")
+                               .arg(Arg::with_name("source_table")
+                                    .short("-s")
+                                    .long("source-table")
+                                    .value_name("SOURCE_TABLE")
+                                    .help("PostGreSQL table whose changes are captured")
+                                    .takes_value(true)
+                                    .required(true))
+                               .arg(Arg::with_name("table_name")
+                                    .short("-t")
+                                    .long("table-name")
+                                    .value_name("TABLE_NAME")
+                                    .help("PostGreSQL Table name (default: events)")
+                                    .takes_value(true))
+                               .arg(Arg::with_name("column_name")
+                                    .short("-c")
+                                    .long("column-name")
+                                    .value_name("COLUMN_NAME")
+                                    .help("PostGreSQL Table column name (default: payload)")
+                                    .takes_value(true))
+                               .arg(Arg::with_name("key_column_name")
+                                    .short("-K")
+                                    .long("key-column-name")
+                                    .value_name("KEY_COLUMN_NAME")
+                                    .help("PostGreSQL Table column name of the message key (default: none)")
+                                    .takes_value(true)
+                                    .requires("key_source_column"))
+                               .arg(Arg::with_name("key_source_column")
+                                    .short("-k")
+                                    .long("key-source-column")
+                                    .value_name("KEY_SOURCE_COLUMN")
+                                    .help("Column of the source table used as message key")
+                                    .takes_value(true)
+                                    .requires("key_column_name"))
+                               .arg(Arg::with_name("pg_url")
+                                    .short("-p")
+                                    .long("pgurl")
+                                    .value_name("PG_URL")
+                                    .help("PostGreSQL URL where triggers are installed (default: postgres://postgres@localhost:5433)")
+                                    .takes_value(true))
+                               .arg(Arg::with_name("install")
+                                    .short("-i")
+                                    .long("install")
+                                    .help("Installs the triggers instead of printing them")))
+                          .subcommand(outbox_subcommand("init", "Creates the events table, its notification function and trigger, unless they already exist"))
+                          .subcommand(outbox_subcommand("migrate", "Upgrades the events table to the latest schema version"))
                           .get_matches();
     
     if let Some(log4rs_file) = matches.value_of("log4rs_file") {
         println!("Configuring logger using {}", log4rs_file);
         log4rs::init_file(log4rs_file, Default::default()).unwrap();
     }
+    if let Some(cdc_matches) = matches.subcommand_matches("cdc") {
+        let source_table = cdc_matches.value_of("source_table").unwrap();
+        let table_name = cdc_matches.value_of("table_name").unwrap_or("events");
+        let column_name = cdc_matches.value_of("column_name").unwrap_or("payload");
+        let key_column_name = cdc_matches.value_of("key_column_name");
+        let key_source_column = cdc_matches.value_of("key_source_column");
+        if cdc_matches.is_present("install") {
+            let pgurl = cdc_matches.value_of("pg_url").unwrap_or("postgres://postgres@localhost:5433");
+            push_the_elephant::install_cdc_triggers(pgurl, source_table, table_name, column_name, key_column_name, key_source_column)
+                .expect("Could not install CDC triggers!");
+        } else {
+            println!("{}", push_the_elephant::generate_cdc_triggers(source_table, table_name, column_name, key_column_name, key_source_column));
+        }
+        return;
+    }
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        return for_each_outbox(init_matches, push_the_elephant::Worker::init_schema);
+    }
+    if let Some(migrate_matches) = matches.subcommand_matches("migrate") {
+        return for_each_outbox(migrate_matches, push_the_elephant::Worker::migrate_schema);
+    }
     if let Some(yaml_file) = matches.value_of("yaml_file") {
         return push_the_elephant::Worker::multi_run(yaml_file).expect("Could not multi run!");
     }
@@ -118,6 +397,26 @@ fn main() {
     if let Some(column_name) = matches.value_of("column_name") {
         builder.column_name(column_name);
     }
+    match matches.value_of("payload_mode") {
+        Some("row") => {
+            let payload_columns = matches.value_of("payload_columns")
+                .map_or(vec![], |x| x.split(",").map(|x| x.trim().to_string()).collect());
+            builder.payload_mode(push_the_elephant::PayloadMode::Row(payload_columns));
+        },
+        Some(_) => {
+            builder.payload_mode(push_the_elephant::PayloadMode::Column);
+        },
+        None => ()
+    }
+    if let Some(key_column_name) = matches.value_of("key_column_name") {
+        builder.key_column_name(key_column_name);
+    }
+    if let Some(headers_column_name) = matches.value_of("headers_column_name") {
+        builder.headers_column_name(headers_column_name);
+    }
+    if let Some(format) = matches.value_of("format") {
+        builder.outbox_format(format.parse().unwrap());
+    }
     if let Some(channel) = matches.value_of("channel") {
         builder.channel(channel);
     }
@@ -130,6 +429,47 @@ fn main() {
     if let Some(notify_timeout_total) = matches.value_of("notify_timeout_total") {
         builder.notify_timeout_total(Duration::from_millis(notify_timeout_total.parse().unwrap()));
     }
+    if let Some(schema_registry_url) = matches.value_of("schema_registry_url") {
+        builder.schema_registry_url(schema_registry_url);
+    }
+    if let Some(avro_schema_file) = matches.value_of("avro_schema_file") {
+        builder.avro_schema_file(avro_schema_file);
+    }
+    if let Some(protobuf_descriptor_set) = matches.value_of("protobuf_descriptor_set") {
+        builder.protobuf_descriptor_set(protobuf_descriptor_set);
+    }
+    if let Some(protobuf_message) = matches.value_of("protobuf_message") {
+        builder.protobuf_message(protobuf_message);
+    }
+    if let Some(dead_letter_topic) = matches.value_of("dead_letter_topic") {
+        builder.dead_letter_topic(dead_letter_topic);
+    }
+    if let Some(quarantine_table) = matches.value_of("quarantine_table") {
+        builder.quarantine_table(quarantine_table);
+    }
+    if let Some(json_schemas) = matches.values_of("json_schema") {
+        builder.json_schemas(json_schemas.map(|x| {
+            let mut topic_and_file = x.splitn(2, "=");
+            let topic = topic_and_file.next().unwrap().trim().to_string();
+            let file = topic_and_file.next().expect("Expected TOPIC=JSON_SCHEMA_FILE").trim().to_string();
+            (topic, file)
+        }).collect());
+    }
+    if let Some(cloud_events_mode) = matches.value_of("cloud_events_mode") {
+        builder.cloud_events_mode(cloud_events_mode.parse::<push_the_elephant::CloudEventsMode>().unwrap());
+    }
+    if let Some(cloud_events_id_column) = matches.value_of("cloud_events_id_column") {
+        builder.cloud_events_id_column(cloud_events_id_column);
+    }
+    if let Some(cloud_events_source_column) = matches.value_of("cloud_events_source_column") {
+        builder.cloud_events_source_column(cloud_events_source_column);
+    }
+    if let Some(cloud_events_type_column) = matches.value_of("cloud_events_type_column") {
+        builder.cloud_events_type_column(cloud_events_type_column);
+    }
+    if let Some(cloud_events_time_column) = matches.value_of("cloud_events_time_column") {
+        builder.cloud_events_time_column(cloud_events_time_column);
+    }
     let worker = builder.build().unwrap();
     log::info!(target: "cli", "Running worker {:?}", worker);
     match worker.run() {
@@ -0,0 +1,185 @@
+pub mod stream {
+    extern crate apache_avro;
+    extern crate ureq;
+    use std::collections::HashMap;
+    use std::error;
+    use std::fs;
+    use std::boxed::Box;
+    use apache_avro::Schema;
+    use apache_avro::types::Value as AvroValue;
+    use serde_json::{json, Value};
+    use crate::common;
+    use log::{info, debug};
+
+    /// The byte starting every message in the Confluent wire format
+    const MAGIC_BYTE: u8 = 0;
+
+    /// A client of a Confluent compatible schema registry
+    pub struct SchemaRegistryClient {
+        url: String,
+        agent: ureq::Agent
+    }
+
+    impl SchemaRegistryClient {
+        /// Returns a new schema registry client
+        ///
+        /// # Arguments
+        ///
+        /// * url - the schema registry URL
+        pub fn new(url: &str) -> SchemaRegistryClient {
+            SchemaRegistryClient{url: url.trim_end_matches('/').to_string(), agent: ureq::Agent::new()}
+        }
+
+        /// Returns the id and the schema of the latest version of a subject
+        pub fn latest(&self, subject: &str) -> Result<(u32, String), Box<error::Error>> {
+            let response: Value = self.agent.get(&format!("{}/subjects/{}/versions/latest", self.url, common::percent_encode(subject)))
+                .call()?
+                .into_json()?;
+            match (response["id"].as_u64(), response["schema"].as_str()) {
+                (Some(id), Some(schema)) => Ok((id as u32, schema.to_string())),
+                _ => Err(Box::from(format!("Invalid schema registry response {}", response)))
+            }
+        }
+
+        /// Registers a schema for a subject, unless already registered, and returns its id
+        pub fn register(&self, subject: &str, schema: &str) -> Result<u32, Box<error::Error>> {
+            let response: Value = self.agent.post(&format!("{}/subjects/{}/versions", self.url, common::percent_encode(subject)))
+                .set("Content-Type", "application/vnd.schemaregistry.v1+json")
+                .send_json(json!({"schema": schema}))?
+                .into_json()?;
+            match response["id"].as_u64() {
+                Some(id) => Ok(id as u32),
+                _ => Err(Box::from(format!("Invalid schema registry response {}", response)))
+            }
+        }
+    }
+
+    /// A stream consumer encoding JSON payloads as Avro in the Confluent wire format, i.e. a
+    /// magic byte, the schema id and the Avro datum, before passing them to another consumer.
+    ///
+    /// Schemas are looked up in the schema registry with the `<topic>-value` subject, and cached.
+    /// Absent payloads, e.g. tombstones, are passed as they are.
+    pub struct AvroStreamConsumer<'a, C: common::StreamConsumer> {
+        /// The consumer receiving the encoded messages
+        consumer: C,
+        /// The schema registry client
+        registry: SchemaRegistryClient,
+        /// The default topic of the messages
+        topic: &'a str,
+        /// The schema registered for every subject, if any
+        schema: Option<String>,
+        /// The schema ids and schemas by subject
+        schemas: HashMap<String, (u32, Schema)>
+    }
+
+    impl <'a, C: common::StreamConsumer> AvroStreamConsumer<'a, C> {
+        /// Returns a new Avro Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the encoded messages
+        /// * registry_url - the schema registry URL
+        /// * topic - the topic of the messages that do not specify their own
+        /// * schema_filename - an optional Avro schema file, registered for every subject. If not
+        /// specified, the latest schema version of every subject is used
+        pub fn new(consumer: C, registry_url: &str, topic: &'a str, schema_filename: Option<&str>) -> Result<AvroStreamConsumer<'a, C>, Box<error::Error>> {
+            info!(target: "avro", "Using schema registry {:?}, schema file {:?}", registry_url, schema_filename);
+            let schema = match schema_filename {
+                Some(x) => Some(fs::read_to_string(x)?),
+                None => None
+            };
+            Ok(AvroStreamConsumer{consumer, registry: SchemaRegistryClient::new(registry_url), topic, schema, schemas: HashMap::new()})
+        }
+
+        fn schema(&mut self, topic: &str) -> Result<&(u32, Schema), Box<error::Error>> {
+            let subject = format!("{}-value", topic);
+            if !self.schemas.contains_key(&subject) {
+                let (id, schema) = match &self.schema {
+                    Some(x) => (self.registry.register(&subject, x)?, x.clone()),
+                    None => self.registry.latest(&subject)?
+                };
+                info!(target: "avro", "Using schema id {:?} for subject {:?}", id, subject);
+                self.schemas.insert(subject.clone(), (id, Schema::parse_str(&schema)?));
+            }
+            Ok(&self.schemas[&subject])
+        }
+    }
+
+    /// Returns a JSON payload encoded as Avro in the Confluent wire format
+    ///
+    /// # Arguments
+    ///
+    /// * id - the schema id
+    /// * schema - the Avro schema
+    /// * payload - the JSON payload
+    pub fn encode_avro(id: u32, schema: &Schema, payload: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+        let json_payload: Value = serde_json::from_slice(payload)?;
+        let value = AvroValue::from(json_payload).resolve(schema)?;
+        let mut result = vec![MAGIC_BYTE];
+        result.extend_from_slice(&id.to_be_bytes());
+        result.extend(apache_avro::to_avro_datum(schema, value)?);
+        Ok(result)
+    }
+
+    impl <'a, C: common::StreamConsumer> common::StreamConsumer for AvroStreamConsumer<'a, C> {
+        fn write(&mut self, mut element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            if let Some(data) = element.data.take() {
+                debug!(target: "avro", "Encoding element {:?}", element.id);
+                let topic = element.topic.as_ref().map_or(self.topic.to_string(), |x| x.to_string());
+                let (id, schema) = self.schema(&topic)?;
+                element.data = Some(encode_avro(*id, schema, &data)?.into_boxed_slice());
+            }
+            self.consumer.write(element)
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.consumer.flush()
+        }
+
+        fn discard(&mut self) {
+            self.consumer.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{AvroStreamConsumer, SchemaRegistryClient};
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{MemoryStreamConsumer, source_element, http_stand_in};
+
+    const LATEST_SCHEMA: &str = r#"{"subject": "events-value", "version": 1, "id": 7, "schema": "{\"type\": \"record\", \"name\": \"Event\", \"fields\": [{\"name\": \"name\", \"type\": \"string\"}, {\"name\": \"count\", \"type\": \"int\"}]}"}"#;
+
+    #[test]
+    fn payloads_are_encoded_with_the_latest_schema() {
+        let (url, requests) = http_stand_in(vec![(200, LATEST_SCHEMA)]);
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        {
+            let mut consumer = AvroStreamConsumer::new(&mut memory_consumer, &url, "events", None).unwrap();
+            consumer.write(source_element("1", r#"{"name": "a", "count": 1}"#)).unwrap();
+            consumer.write(source_element("2", r#"{"name": "b", "count": 2}"#)).unwrap();
+            consumer.flush().unwrap();
+        }
+        assert_eq!(vec![("GET /subjects/events-value/versions/latest HTTP/1.1".to_string(), "".to_string())], *requests.lock().unwrap());
+        assert_eq!(2, memory_consumer.elements.len());
+        assert_eq!(1, memory_consumer.flushes);
+        assert_eq!(Some(Box::from(&[0, 0, 0, 0, 7, 2, b'a', 2][..])), memory_consumer.elements[0].data);
+        assert_eq!(Some(Box::from(&[0, 0, 0, 0, 7, 2, b'b', 4][..])), memory_consumer.elements[1].data);
+    }
+
+    #[test]
+    fn subjects_are_percent_encoded() {
+        let (url, requests) = http_stand_in(vec![(200, LATEST_SCHEMA)]);
+        let client = SchemaRegistryClient::new(&url);
+        client.latest("orders/eu events-value").unwrap();
+        assert_eq!("GET /subjects/orders%2Feu%20events-value/versions/latest HTTP/1.1", requests.lock().unwrap()[0].0);
+    }
+
+    #[test]
+    fn payloads_not_matching_the_schema_are_rejected() {
+        let (url, _) = http_stand_in(vec![(200, LATEST_SCHEMA)]);
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        let mut consumer = AvroStreamConsumer::new(&mut memory_consumer, &url, "events", None).unwrap();
+        assert!(consumer.write(source_element("1", r#"{"name": "a"}"#)).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+pub mod triggers {
+    extern crate postgres;
+    use std::error;
+    use std::boxed::Box;
+    use postgres::{Connection, TlsMode};
+    use log::info;
+
+    /// Generates the SQL that installs change data capture triggers on a business table.
+    ///
+    /// Every INSERT, UPDATE and DELETE on `source_table` writes a row into the outbox table, whose
+    /// payload is a JSON object with the following fields:
+    /// * `op` - the operation, i.e. `INSERT`, `UPDATE` or `DELETE`
+    /// * `schema` - the schema of the source table
+    /// * `table` - the name of the source table
+    /// * `old` - the row image before the change, `null` for inserts
+    /// * `new` - the row image after the change, `null` for deletes
+    ///
+    /// The generated SQL can be executed more than once, as existing triggers are replaced.
+    ///
+    /// Arguments:
+    /// * `source_table` - the business table whose changes are captured
+    /// * `table_name` - the outbox table
+    /// * `column_name` - the column in the outbox table where the payload is written
+    /// * `key_column_name` - the optional column in the outbox table where the message key is written
+    /// * `key_source_column` - the column in the source table whose value is used as message key.
+    /// It is ignored when `key_column_name` is not specified
+    pub fn generate_cdc_triggers(source_table: &str, table_name: &str, column_name: &str, key_column_name: Option<&str>, key_source_column: Option<&str>) -> String {
+        let trigger_name = format!("pte_cdc_{}", source_table.replace(".", "_"));
+        let (key_column, key_value) = match (key_column_name, key_source_column) {
+            (Some(c), Some(s)) => (format!(", {}", c), format!(",\n    coalesce(new_image, old_image) ->> '{}'", s)),
+            _ => (String::new(), String::new())
+        };
+        format!(
+"CREATE OR REPLACE FUNCTION {trigger_name} ()
+ returns trigger
+ language plpgsql
+as $$
+declare
+  old_image json;
+  new_image json;
+begin
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    old_image := row_to_json(OLD);
+  END IF;
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    new_image := row_to_json(NEW);
+  END IF;
+  INSERT INTO {table_name}({column_name}{key_column}) VALUES (
+    json_build_object(
+      'op', TG_OP,
+      'schema', TG_TABLE_SCHEMA,
+      'table', TG_TABLE_NAME,
+      'old', old_image,
+      'new', new_image
+    )::text{key_value}
+  );
+  RETURN NULL;
+end;
+$$;
+
+DROP TRIGGER IF EXISTS {trigger_name} ON {source_table};
+
+CREATE TRIGGER {trigger_name}
+         AFTER INSERT OR UPDATE OR DELETE
+            ON {source_table}
+      FOR EACH ROW
+       EXECUTE PROCEDURE {trigger_name}();
+",
+            trigger_name = trigger_name,
+            source_table = source_table,
+            table_name = table_name,
+            column_name = column_name,
+            key_column = key_column,
+            key_value = key_value
+        )
+    }
+
+    /// Installs change data capture triggers on a business table, as described in
+    /// [generate_cdc_triggers](fn.generate_cdc_triggers.html).
+    ///
+    /// Arguments:
+    /// * `url` - PostGreSQL connection URL
+    /// * the other arguments are the same as [generate_cdc_triggers](fn.generate_cdc_triggers.html)
+    pub fn install_cdc_triggers(url: &str, source_table: &str, table_name: &str, column_name: &str, key_column_name: Option<&str>, key_source_column: Option<&str>) -> Result<(), Box<error::Error>> {
+        info!(target: "cdc", "Installing CDC triggers on table {:?}, outbox table {:?}", source_table, table_name);
+        let conn = Connection::connect(url, TlsMode::None)?;
+        let transaction = conn.transaction()?;
+        transaction.batch_execute(&generate_cdc_triggers(source_table, table_name, column_name, key_column_name, key_source_column))?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::triggers;
+
+    #[test]
+    fn cdc_triggers_with_key() {
+        let sql = triggers::generate_cdc_triggers("public.orders", "events", "payload", Some("key"), Some("order_id"));
+        assert!(sql.contains("CREATE OR REPLACE FUNCTION pte_cdc_public_orders ()"));
+        assert!(sql.contains("INSERT INTO events(payload, key) VALUES ("));
+        assert!(sql.contains("coalesce(new_image, old_image) ->> 'order_id'"));
+        assert!(sql.contains("AFTER INSERT OR UPDATE OR DELETE\n            ON public.orders"));
+    }
+
+    #[test]
+    fn cdc_triggers_without_key() {
+        let sql = triggers::generate_cdc_triggers("orders", "events", "payload", None, Some("order_id"));
+        assert!(sql.contains("INSERT INTO events(payload) VALUES ("));
+        assert!(!sql.contains("order_id"));
+    }
+}
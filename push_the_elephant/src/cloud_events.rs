@@ -0,0 +1,275 @@
+pub mod format {
+    extern crate base64;
+    extern crate chrono;
+    use std::error;
+    use std::fmt;
+    use std::str::FromStr;
+    use serde_json::{json, Map, Value};
+    use crate::common;
+
+    /// The CloudEvents specification version
+    pub const CLOUD_EVENTS_SPEC_VERSION: &str = "1.0";
+
+    /// The default type of the events, when no type column is configured
+    pub const CLOUD_EVENTS_DEFAULT_TYPE: &str = "push_the_elephant.event";
+
+    /// How messages are wrapped as CloudEvents
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum CloudEventsMode {
+        /// The message value is a JSON event envelope, having the payload as data
+        Structured,
+        /// The payload is left unchanged and the event attributes are sent as `ce_*` headers
+        Binary
+    }
+
+    #[derive(Debug)]
+    pub struct CloudEventsModeError {
+        mode: String
+    }
+
+    impl error::Error for CloudEventsModeError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for CloudEventsModeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid CloudEvents mode {:?}", self.mode)
+        }
+    }
+
+    impl FromStr for CloudEventsMode {
+        type Err = CloudEventsModeError;
+
+        fn from_str(s: &str) -> Result<CloudEventsMode, CloudEventsModeError> {
+            match s {
+                "structured" => Ok(CloudEventsMode::Structured),
+                "binary" => Ok(CloudEventsMode::Binary),
+                _ => Err(CloudEventsModeError{mode: s.to_string()})
+            }
+        }
+    }
+
+    /// The prefix of the headers carrying the event attributes, as in the Kafka binary mode
+    pub const CLOUD_EVENTS_HEADER_PREFIX: &str = "ce_";
+
+    /// The columns the CloudEvents 1.0 attributes are read from
+    #[derive(Debug, Clone)]
+    pub struct CloudEventsColumns<'a> {
+        /// The column of the `id` attribute
+        pub id_column: &'a str,
+        /// The column of the `source` attribute. When not specified, the table name is used
+        pub source_column: Option<&'a str>,
+        /// The column of the `type` attribute. When not specified,
+        /// [CLOUD_EVENTS_DEFAULT_TYPE](constant.CLOUD_EVENTS_DEFAULT_TYPE.html) is used
+        pub type_column: Option<&'a str>,
+        /// The column of the `time` attribute. When not specified, or when the column is null,
+        /// events have no time
+        pub time_column: Option<&'a str>
+    }
+
+    /// Returns the `time` attribute of a column value, in RFC 3339 format. Timestamps without
+    /// time zone are taken as UTC.
+    pub(crate) fn event_time(value: String) -> String {
+        if chrono::DateTime::parse_from_rfc3339(&value).is_ok() {
+            return value;
+        }
+        match chrono::NaiveDateTime::parse_from_str(&value, "%Y-%m-%dT%H:%M:%S%.f") {
+            Ok(x) => x.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string(),
+            Err(_) => value
+        }
+    }
+
+    impl <'a> CloudEventsColumns<'a> {
+        /// Returns the columns the event attributes are read from
+        pub fn columns(&self) -> Vec<&'a str> {
+            let mut result = vec![self.id_column];
+            result.extend(self.source_column.iter().chain(self.type_column.iter()).chain(self.time_column.iter()).cloned());
+            result
+        }
+
+        /// Returns the attribute read from a row column, if any
+        fn attribute(row: &Value, column: Option<&str>) -> Option<String> {
+            match &row[column?] {
+                Value::Null => None,
+                Value::String(x) => Some(x.clone()),
+                x => Some(x.to_string())
+            }
+        }
+
+        /// Returns the event attributes of a row as `ce_*` headers, i.e. as sent in binary mode.
+        /// In structured mode, the [CloudEventsStreamConsumer](struct.CloudEventsStreamConsumer.html)
+        /// moves them into the event envelope.
+        ///
+        /// Arguments:
+        /// * `row` - the table row of the message, as a JSON object
+        /// * `table_name` - the table of the row, used as default source
+        pub fn headers(&self, row: &Value, table_name: &str) -> Result<Vec<(Box<str>, Box<str>)>, Box<error::Error>> {
+            let id = match Self::attribute(row, Some(self.id_column)) {
+                Some(x) => x,
+                None => {
+                    return Err(Box::from(format!("No such CloudEvents id column {}", self.id_column)));
+                }
+            };
+            let source = Self::attribute(row, self.source_column).unwrap_or_else(|| table_name.to_string());
+            let event_type = Self::attribute(row, self.type_column).unwrap_or_else(|| CLOUD_EVENTS_DEFAULT_TYPE.to_string());
+            let mut headers = vec![
+                (Box::from("ce_specversion"), Box::from(CLOUD_EVENTS_SPEC_VERSION)),
+                (Box::from("ce_id"), Box::from(id)),
+                (Box::from("ce_source"), Box::from(source)),
+                (Box::from("ce_type"), Box::from(event_type))
+            ];
+            if let Some(x) = Self::attribute(row, self.time_column).map(event_time) {
+                headers.push((Box::from("ce_time"), Box::from(x)));
+            }
+            Ok(headers)
+        }
+    }
+
+    /// A stream consumer wrapping messages as structured CloudEvents, i.e. JSON event envelopes
+    /// having the payload as data, before passing them to another consumer. It comes last, right
+    /// before the sink, so that transforms, validation and encoders see the event data.
+    ///
+    /// The event attributes are the `ce_*` headers, which are removed. Tombstones are passed
+    /// without those headers and left as they are, so that compacted topics keep working.
+    pub struct CloudEventsStreamConsumer<C: common::StreamConsumer> {
+        /// The consumer receiving the wrapped messages
+        consumer: C
+    }
+
+    impl <C: common::StreamConsumer> CloudEventsStreamConsumer<C> {
+        /// Returns a new CloudEvents Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the wrapped messages
+        pub fn new(consumer: C) -> CloudEventsStreamConsumer<C> {
+            CloudEventsStreamConsumer{consumer}
+        }
+    }
+
+    impl <C: common::StreamConsumer> common::StreamConsumer for CloudEventsStreamConsumer<C> {
+        fn write(&mut self, mut element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let (attributes, headers): (Vec<(Box<str>, Box<str>)>, Vec<(Box<str>, Box<str>)>) = element.headers.into_iter()
+                .partition(|(name, _)| name.starts_with(CLOUD_EVENTS_HEADER_PREFIX));
+            element.headers = headers;
+            if let Some(data) = element.data.take() {
+                let mut event = Map::new();
+                for (name, value) in attributes.into_iter() {
+                    event.insert(name[CLOUD_EVENTS_HEADER_PREFIX.len()..].to_string(), json!(&*value));
+                }
+                match serde_json::from_slice::<Value>(&data) {
+                    Ok(x) => {
+                        event.insert("datacontenttype".to_string(), json!("application/json"));
+                        event.insert("data".to_string(), x);
+                    },
+                    Err(_) => {
+                        event.insert("data_base64".to_string(), json!(base64::encode(&data)));
+                    }
+                }
+                element.data = Some(Box::from(serde_json::to_vec(&Value::Object(event))?.as_slice()));
+                element.headers.push((Box::from("content-type"), Box::from("application/cloudevents+json")));
+            }
+            self.consumer.write(element)
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.consumer.flush()
+        }
+
+        fn discard(&mut self) {
+            self.consumer.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::format::{CloudEventsColumns, CloudEventsStreamConsumer, event_time};
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, MemoryStreamConsumer};
+
+    fn cloud_events_columns() -> CloudEventsColumns<'static> {
+        CloudEventsColumns{id_column: "id", source_column: None, type_column: Some("event_type"), time_column: Some("created_at")}
+    }
+
+    /// Returns the elements of a row wrapped by a structured CloudEvents consumer
+    fn structured(mut element: crate::common::SourceElement, row: &Value) -> Vec<crate::common::SourceElement> {
+        element.headers.extend(cloud_events_columns().headers(row, "events").unwrap());
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        {
+            let mut consumer = CloudEventsStreamConsumer::new(&mut memory_consumer);
+            consumer.write(element).unwrap();
+            consumer.flush().unwrap();
+        }
+        memory_consumer.elements
+    }
+
+    #[test]
+    fn structured_events() {
+        let row = json!({"id": 3, "event_type": "order.created", "created_at": "2019-08-01T10:00:00+00:00"});
+        let mut element = source_element("3", r#"{"amount": 10}"#);
+        element.headers.push((Box::from("origin"), Box::from("orders")));
+        let element = structured(element, &row).remove(0);
+        assert_eq!(
+            vec![
+                (Box::from("origin"), Box::from("orders")),
+                (Box::from("content-type"), Box::from("application/cloudevents+json"))
+            ],
+            element.headers
+        );
+        let event: Value = serde_json::from_slice(&element.data.unwrap()).unwrap();
+        assert_eq!(json!({
+            "specversion": "1.0",
+            "id": "3",
+            "source": "events",
+            "type": "order.created",
+            "time": "2019-08-01T10:00:00+00:00",
+            "datacontenttype": "application/json",
+            "data": {"amount": 10}
+        }), event);
+    }
+
+    #[test]
+    fn structured_tombstones_are_left_as_they_are() {
+        let row = json!({"id": 3, "event_type": "order.created", "created_at": null});
+        let mut element = source_element("3", "");
+        element.data = None;
+        let element = structured(element, &row).remove(0);
+        assert_eq!(None, element.data);
+        assert!(element.headers.is_empty());
+    }
+
+    #[test]
+    fn binary_events() {
+        let row = json!({"id": 3, "event_type": "order.created", "created_at": "2019-08-01T10:00:00+00:00"});
+        assert_eq!(
+            vec![
+                (Box::from("ce_specversion"), Box::from("1.0")),
+                (Box::from("ce_id"), Box::from("3")),
+                (Box::from("ce_source"), Box::from("events")),
+                (Box::from("ce_type"), Box::from("order.created")),
+                (Box::from("ce_time"), Box::from("2019-08-01T10:00:00+00:00"))
+            ],
+            cloud_events_columns().headers(&row, "events").unwrap()
+        );
+    }
+
+    #[test]
+    fn events_without_time() {
+        let row = json!({"id": 3, "event_type": "order.created", "created_at": null});
+        assert!(cloud_events_columns().headers(&row, "events").unwrap().iter().all(|x| &*x.0 != "ce_time"));
+        let element = structured(source_element("3", "{}"), &row).remove(0);
+        let event: Value = serde_json::from_slice(&element.data.unwrap()).unwrap();
+        assert_eq!(None, event.get("time"));
+    }
+
+    #[test]
+    fn naive_times_are_utc() {
+        assert_eq!("2019-08-01T10:00:00Z", event_time("2019-08-01T10:00:00".to_string()));
+        assert_eq!("2019-08-01T10:00:00.123456Z", event_time("2019-08-01T10:00:00.123456".to_string()));
+        assert_eq!("2019-08-01T12:00:00+02:00", event_time("2019-08-01T12:00:00+02:00".to_string()));
+    }
+}
@@ -1,13 +1,20 @@
 use std::boxed::Box;
+use std::collections::HashSet;
 use std::error;
 
 /// The message that is going to be moved from PostGreSQL to Kafka
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SourceElement {
     /// The message id
     pub id: Box<str>,
-    /// The data in the message
-    pub data: Box<[u8]>
+    /// The message key, if any
+    pub key: Option<Box<str>>,
+    /// The data in the message. `None` stands for an absent value, e.g. a tombstone
+    pub data: Option<Box<[u8]>>,
+    /// The message headers, as name and value pairs
+    pub headers: Vec<(Box<str>, Box<str>)>,
+    /// The topic where the message is sent. `None` stands for the default one of the consumer
+    pub topic: Option<Box<str>>
 }
 
 /// Produces [SourceElement](struct.SourceElement.html) objects from its origin and sends them to a [StreamConsumer](trait.StreamConsumer.html)
@@ -34,4 +41,143 @@ pub trait StreamConsumer {
     /// # Arguments:
     /// `&mut self` - the consumer itself
     fn flush(&mut self) -> Result<(), Box<error::Error>>;
+    /// Discards the messages that have been received but not flushed yet, e.g. after a failure
+    /// that is ignored, so that they are not sent again by the next flush. Consumers sending
+    /// messages as they are received have nothing to discard.
+    ///
+    /// # Arguments:
+    /// `&mut self` - the consumer itself
+    fn discard(&mut self) {}
+}
+
+impl <T: StreamConsumer + ?Sized> StreamConsumer for Box<T> {
+    fn write(&mut self, element: SourceElement) -> Result<(), Box<error::Error>> {
+        (**self).write(element)
+    }
+
+    fn flush(&mut self) -> Result<(), Box<error::Error>> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self) {
+        (**self).discard()
+    }
+}
+
+impl <'a, T: StreamConsumer + ?Sized> StreamConsumer for &'a mut T {
+    fn write(&mut self, element: SourceElement) -> Result<(), Box<error::Error>> {
+        (**self).write(element)
+    }
+
+    fn flush(&mut self) -> Result<(), Box<error::Error>> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self) {
+        (**self).discard()
+    }
+}
+
+/// Returns a URL component with every character but the unreserved ones percent-encoded, e.g. to
+/// substitute values in URL paths
+pub(crate) fn percent_encode(s: &str) -> String {
+    s.bytes().map(|x| match x {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (x as char).to_string(),
+        _ => format!("%{:02X}", x)
+    }).collect()
+}
+
+/// Returns the indexes of the first element of every key, in order and up to the specified number.
+/// Sending only these elements in a batch that may partially fail, and the next ones once they
+/// have succeeded, keeps the elements of every key in order.
+///
+/// # Arguments
+///
+/// * elements - the elements to be sent, in order
+/// * key - the ordering key of an element
+/// * max - the largest number of returned indexes
+pub(crate) fn first_of_every_key<F: Fn(&SourceElement) -> &str>(elements: &[&SourceElement], key: F, max: usize) -> Vec<usize> {
+    let mut keys = HashSet::new();
+    let mut result = Vec::new();
+    for (i, element) in elements.iter().enumerate() {
+        if result.len() >= max {
+            break;
+        }
+        if keys.insert(key(element)) {
+            result.push(i);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use super::{SourceElement, StreamConsumer};
+
+    /// A [StreamConsumer](../trait.StreamConsumer.html) keeping the written elements in memory
+    #[derive(Debug, Default)]
+    pub struct MemoryStreamConsumer {
+        pub elements: Vec<SourceElement>,
+        pub flushes: usize
+    }
+
+    impl StreamConsumer for MemoryStreamConsumer {
+        fn write(&mut self, element: SourceElement) -> Result<(), Box<error::Error>> {
+            self.elements.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /// Returns a [SourceElement](../struct.SourceElement.html) with the specified id and data
+    pub fn source_element(id: &str, data: &str) -> SourceElement {
+        SourceElement{id: Box::from(id), key: None, data: Some(Box::from(data.as_bytes())), headers: Vec::new(), topic: None}
+    }
+
+    /// Starts a local HTTP server answering requests with the specified status codes and bodies,
+    /// in order. Returns the server URL and the received requests, as request line and body.
+    pub fn http_stand_in(responses: Vec<(u16, &'static str)>) -> (String, Arc<Mutex<Vec<(String, String)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let thread_requests = Arc::clone(&requests);
+        thread::spawn(move || {
+            for (stream, (status, body)) in listener.incoming().zip(responses.into_iter()) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lowercase_header = header.to_lowercase();
+                    if lowercase_header.starts_with("content-length:") {
+                        content_length = lowercase_header["content-length:".len()..].trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                thread_requests.lock().unwrap().push((request_line.trim().to_string(), String::from_utf8_lossy(&request_body).to_string()));
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stand-in\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                ).unwrap();
+            }
+        });
+        (url, requests)
+    }
 }
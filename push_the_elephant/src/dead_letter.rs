@@ -0,0 +1,68 @@
+pub mod stream {
+    use std::error;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::common;
+    use log::warn;
+
+    /// The header carrying the reason why a message has been sent to the dead letter destination
+    pub const DEAD_LETTER_ERROR_HEADER: &str = "pte.error";
+
+    /// The destination of the messages that can not be sent, e.g. because their payload is invalid.
+    /// Clones share the same consumer, so that a worker encoding and validating messages opens
+    /// a single connection to the dead letter destination.
+    #[derive(Clone)]
+    pub struct DeadLetterQueue<'a> {
+        /// The consumer receiving the rejected messages, if any
+        consumer: Option<Rc<RefCell<Box<common::StreamConsumer + 'a>>>>,
+        /// Whether the topic of the rejected messages is reset
+        reset_topic: bool
+    }
+
+    impl <'a> DeadLetterQueue<'a> {
+        /// Returns a new dead letter queue
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the rejected messages, with their topic. When not
+        /// specified, rejecting a message fails with the rejection error
+        pub fn new(consumer: Option<Box<common::StreamConsumer + 'a>>) -> DeadLetterQueue<'a> {
+            DeadLetterQueue{consumer: consumer.map(|x| Rc::new(RefCell::new(x))), reset_topic: false}
+        }
+
+        /// Returns a new dead letter queue resetting the topic of the rejected messages, so that
+        /// the default topic of the consumer is used, e.g. the dead letter topic of a Kafka consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the rejected messages
+        pub fn with_default_topic(consumer: Box<common::StreamConsumer + 'a>) -> DeadLetterQueue<'a> {
+            DeadLetterQueue{consumer: Some(Rc::new(RefCell::new(consumer))), reset_topic: true}
+        }
+
+        /// Sends a message to the dead letter destination, with the rejection error in the
+        /// [DEAD_LETTER_ERROR_HEADER](constant.DEAD_LETTER_ERROR_HEADER.html) header.
+        pub fn reject(&mut self, mut element: common::SourceElement, error: Box<error::Error>) -> Result<(), Box<error::Error>> {
+            match self.consumer.as_ref() {
+                Some(consumer) => {
+                    warn!(target: "dead_letter", "Sending element {:?} to the dead letter destination: {}", element.id, error);
+                    element.headers.push((Box::from(DEAD_LETTER_ERROR_HEADER), Box::from(error.to_string())));
+                    if self.reset_topic {
+                        element.topic = None;
+                    }
+                    consumer.borrow_mut().write(element)
+                },
+                None => Err(error)
+            }
+        }
+
+        /// Flushes the rejected messages
+        pub fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            match self.consumer.as_ref() {
+                Some(consumer) => consumer.borrow_mut().flush(),
+                None => Ok(())
+            }
+        }
+    }
+}
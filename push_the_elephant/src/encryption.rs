@@ -0,0 +1,246 @@
+pub mod transform {
+    extern crate aes_gcm;
+    extern crate base64;
+    use std::collections::HashMap;
+    use std::error;
+    use std::fmt;
+    use std::fs;
+    use std::boxed::Box;
+    use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+    use aes_gcm::aead::{Aead, AeadCore, OsRng};
+    use serde_json::Value;
+    use crate::common;
+    use crate::transform::pipeline::Transform;
+    use log::{info, debug};
+
+    /// The header carrying the id of the key the message has been encrypted with
+    pub const KEY_ID_HEADER: &str = "pte.key-id";
+
+    /// The header carrying the JSON pointers of the encrypted fields, comma separated.
+    /// When absent, the whole payload is encrypted.
+    pub const ENCRYPTED_FIELDS_HEADER: &str = "pte.encrypted-fields";
+
+    /// The length of the AES-GCM nonce, prepended to every ciphertext
+    const NONCE_LENGTH: usize = 12;
+
+    /// A set of AES-256 keys by id, one of them being used for encryption.
+    ///
+    /// Keyring files are JSON objects like
+    /// `{"active": "2019-08", "keys": {"2019-07": "<base64 key>", "2019-08": "<base64 key>"}}`,
+    /// so that keys can be rotated while older messages can still be decrypted.
+    pub struct Keyring {
+        active: String,
+        keys: HashMap<String, Vec<u8>>
+    }
+
+    impl fmt::Debug for Keyring {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let mut ids: Vec<&String> = self.keys.keys().collect();
+            ids.sort();
+            write!(f, "Keyring {{ active: {:?}, keys: {:?} }}", self.active, ids)
+        }
+    }
+
+    impl Keyring {
+        /// Returns a keyring from its JSON representation
+        pub fn from_json(json: &str) -> Result<Keyring, Box<error::Error>> {
+            let value: Value = serde_json::from_str(json)?;
+            let active = match value["active"].as_str() {
+                Some(x) => x.to_string(),
+                None => {
+                    return Err(Box::from("No active key in keyring"));
+                }
+            };
+            let mut keys = HashMap::new();
+            for (id, key) in value["keys"].as_object().into_iter().flat_map(|x| x.iter()) {
+                let key = base64::decode(key.as_str().ok_or_else(|| format!("Key {} is not a string", id))?)?;
+                if key.len() != 32 {
+                    return Err(Box::from(format!("Key {} is not a 256 bits key", id)));
+                }
+                keys.insert(id.clone(), key);
+            }
+            if !keys.contains_key(&active) {
+                return Err(Box::from(format!("No such active key {} in keyring", active)));
+            }
+            Ok(Keyring{active, keys})
+        }
+
+        /// Returns a keyring read from a file
+        pub fn from_file(filename: &str) -> Result<Keyring, Box<error::Error>> {
+            info!(target: "encryption", "Using keyring {:?}", filename);
+            Self::from_json(&fs::read_to_string(filename)?)
+        }
+
+        fn cipher(&self, key_id: &str) -> Result<Aes256Gcm, Box<error::Error>> {
+            match self.keys.get(key_id) {
+                Some(x) => Ok(Aes256Gcm::new_from_slice(x).map_err(|e| e.to_string())?),
+                None => Err(Box::from(format!("No such key {} in keyring", key_id)))
+            }
+        }
+
+        /// Returns the nonce followed by the ciphertext of some data, encrypted with the active key
+        pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let mut result = nonce.to_vec();
+            result.extend(self.cipher(&self.active)?.encrypt(&nonce, data).map_err(|e| e.to_string())?);
+            Ok(result)
+        }
+
+        /// Returns the data encrypted with [encrypt](#method.encrypt) using the specified key
+        pub fn decrypt(&self, key_id: &str, data: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+            if data.len() < NONCE_LENGTH {
+                return Err(Box::from("Encrypted data is too short"));
+            }
+            let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+            Ok(self.cipher(key_id)?.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|e| e.to_string())?)
+        }
+    }
+
+    /// A [Transform](trait.Transform.html) encrypting JSON fields of the payloads, or the whole
+    /// payloads, with AES-256-GCM. Encrypted fields are replaced by the base64 encoding of the
+    /// encrypted JSON value, while encrypted payloads are the nonce followed by the ciphertext.
+    /// The key id is sent in the [KEY_ID_HEADER](constant.KEY_ID_HEADER.html) header and the
+    /// encrypted fields in the [ENCRYPTED_FIELDS_HEADER](constant.ENCRYPTED_FIELDS_HEADER.html) one.
+    /// Absent payloads, e.g. tombstones, and missing fields are left as they are.
+    #[derive(Debug)]
+    pub struct EncryptTransform {
+        keyring: Keyring,
+        fields: Vec<String>
+    }
+
+    impl EncryptTransform {
+        /// Returns a new Encrypt Transform
+        ///
+        /// # Arguments
+        ///
+        /// * keyring - the keys, the active one being used
+        /// * fields - the JSON pointers of the encrypted fields. When empty, whole payloads are encrypted
+        pub fn new(keyring: Keyring, fields: Vec<String>) -> EncryptTransform {
+            EncryptTransform{keyring, fields}
+        }
+    }
+
+    impl Transform for EncryptTransform {
+        fn apply(&self, mut element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            let data = match element.data.take() {
+                Some(x) => x,
+                None => {
+                    return Ok(vec![element]);
+                }
+            };
+            debug!(target: "encryption", "Encrypting element {:?}", element.id);
+            if self.fields.is_empty() {
+                element.data = Some(self.keyring.encrypt(&data)?.into_boxed_slice());
+            } else {
+                let mut payload: Value = serde_json::from_slice(&data)?;
+                let mut encrypted_fields = Vec::new();
+                for path in self.fields.iter() {
+                    if let Some(field) = payload.pointer_mut(path) {
+                        let encrypted = self.keyring.encrypt(&serde_json::to_vec(field)?)?;
+                        *field = Value::String(base64::encode(&encrypted));
+                        encrypted_fields.push(path.as_str());
+                    }
+                }
+                element.data = Some(serde_json::to_vec(&payload)?.into_boxed_slice());
+                element.headers.push((Box::from(ENCRYPTED_FIELDS_HEADER), Box::from(encrypted_fields.join(","))));
+            }
+            element.headers.push((Box::from(KEY_ID_HEADER), Box::from(self.keyring.active.as_str())));
+            Ok(vec![element])
+        }
+    }
+
+    /// Returns a payload encrypted by an [EncryptTransform](struct.EncryptTransform.html), decrypted
+    ///
+    /// # Arguments
+    ///
+    /// * keyring - the keys, including the one the payload has been encrypted with
+    /// * key_id - the value of the [KEY_ID_HEADER](constant.KEY_ID_HEADER.html) header
+    /// * encrypted_fields - the value of the [ENCRYPTED_FIELDS_HEADER](constant.ENCRYPTED_FIELDS_HEADER.html)
+    /// header, if any
+    /// * payload - the encrypted payload
+    pub fn decrypt_payload(keyring: &Keyring, key_id: &str, encrypted_fields: Option<&str>, payload: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+        let fields = match encrypted_fields {
+            Some(x) => x,
+            None => {
+                return keyring.decrypt(key_id, payload);
+            }
+        };
+        let mut result: Value = serde_json::from_slice(payload)?;
+        for path in fields.split(',').filter(|x| !x.is_empty()) {
+            if let Some(field) = result.pointer_mut(path) {
+                let encrypted = match field.as_str() {
+                    Some(x) => base64::decode(x)?,
+                    None => {
+                        return Err(Box::from(format!("Encrypted field {} is not a string", path)));
+                    }
+                };
+                *field = serde_json::from_slice(&keyring.decrypt(key_id, &encrypted)?)?;
+            }
+        }
+        Ok(serde_json::to_vec(&result)?)
+    }
+
+    /// Returns an element encrypted by an [EncryptTransform](struct.EncryptTransform.html), decrypted
+    /// and without the encryption headers. Elements without the key id header are returned as they are.
+    pub fn decrypt_element(keyring: &Keyring, mut element: common::SourceElement) -> Result<common::SourceElement, Box<error::Error>> {
+        let header = |name: &str| element.headers.iter().find(|x| &*x.0 == name).map(|x| x.1.to_string());
+        let key_id = match header(KEY_ID_HEADER) {
+            Some(x) => x,
+            None => {
+                return Ok(element);
+            }
+        };
+        let encrypted_fields = header(ENCRYPTED_FIELDS_HEADER);
+        if let Some(data) = element.data.take() {
+            element.data = Some(decrypt_payload(keyring, &key_id, encrypted_fields.as_ref().map(|x| x.as_str()), &data)?.into_boxed_slice());
+        }
+        element.headers.retain(|x| &*x.0 != KEY_ID_HEADER && &*x.0 != ENCRYPTED_FIELDS_HEADER);
+        Ok(element)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::transform::*;
+    use crate::common::tests::source_element;
+    use crate::transform::pipeline::Transform;
+
+    const KEYRING: &str = r#"{
+        "active": "k2",
+        "keys": {
+            "k1": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "k2": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+        }
+    }"#;
+
+    #[test]
+    fn fields_are_encrypted_and_decrypted() {
+        let transform = EncryptTransform::new(Keyring::from_json(KEYRING).unwrap(), vec!["/email".to_string(), "/card/number".to_string(), "/missing".to_string()]);
+        let mut result = transform.apply(source_element("1", r#"{"id": 1, "email": "a@b.c", "card": {"number": 1234}}"#)).unwrap();
+        let encrypted: Value = serde_json::from_slice(result[0].data.as_ref().unwrap()).unwrap();
+        assert_eq!(json!(1), encrypted["id"]);
+        assert!(encrypted["email"].is_string());
+        assert_ne!(json!("a@b.c"), encrypted["email"]);
+        assert!(encrypted["card"]["number"].is_string());
+        assert_eq!(
+            vec![(Box::from(ENCRYPTED_FIELDS_HEADER), Box::from("/email,/card/number")), (Box::from(KEY_ID_HEADER), Box::from("k2"))],
+            result[0].headers
+        );
+        let decrypted = decrypt_element(&Keyring::from_json(KEYRING).unwrap(), result.remove(0)).unwrap();
+        let payload: Value = serde_json::from_slice(decrypted.data.as_ref().unwrap()).unwrap();
+        assert_eq!(json!({"id": 1, "email": "a@b.c", "card": {"number": 1234}}), payload);
+        assert!(decrypted.headers.is_empty());
+    }
+
+    #[test]
+    fn payloads_are_encrypted_and_decrypted() {
+        let keyring = Keyring::from_json(KEYRING).unwrap();
+        let transform = EncryptTransform::new(Keyring::from_json(KEYRING).unwrap(), vec![]);
+        let result = transform.apply(source_element("1", "not json")).unwrap();
+        let data = result[0].data.as_ref().unwrap();
+        assert_ne!(&b"not json"[..], &**data);
+        assert_eq!(b"not json".to_vec(), decrypt_payload(&keyring, "k2", None, data).unwrap());
+        assert!(decrypt_payload(&keyring, "k1", None, data).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+pub mod stream {
+    extern crate base64;
+    use std::error;
+    use std::fmt;
+    use std::fs;
+    use std::io::{ErrorKind, Write};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use std::boxed::Box;
+    use serde_json::{json, Map, Value};
+    use crate::common;
+    use log::{info, debug};
+
+    /// How payloads are written in the JSON lines
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum FilePayloadEncoding {
+        /// Payloads are written as strings, except those that are not UTF-8, which are written as base64
+        String,
+        /// Payloads are always written as base64
+        Base64
+    }
+
+    #[derive(Debug)]
+    pub struct FilePayloadEncodingError {
+        encoding: String
+    }
+
+    impl error::Error for FilePayloadEncodingError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for FilePayloadEncodingError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid payload encoding {:?}", self.encoding)
+        }
+    }
+
+    impl FromStr for FilePayloadEncoding {
+        type Err = FilePayloadEncodingError;
+
+        fn from_str(s: &str) -> Result<FilePayloadEncoding, FilePayloadEncodingError> {
+            match s {
+                "string" => Ok(FilePayloadEncoding::String),
+                "base64" => Ok(FilePayloadEncoding::Base64),
+                _ => Err(FilePayloadEncodingError{encoding: s.to_string()})
+            }
+        }
+    }
+
+    struct CurrentFile {
+        file: fs::File,
+        /// The lines written since the last flush
+        pending: Vec<u8>,
+        /// The size of the file at the last flush
+        synced: u64,
+        created: Instant
+    }
+
+    /// A stream consumer writing messages as JSON lines to files, which are rotated by size and age.
+    ///
+    /// Every line is a JSON object with the `id`, `key`, `topic`, `headers`, `payload` and
+    /// `timestamp` fields, plus `"payload_encoding": "base64"` when the payload is written as base64.
+    /// Files are named `<prefix>-<UTC time>.jsonl` and a new file is created at every start.
+    /// Lines are written on flush, which succeeds only once they have been synced to disk. A failed
+    /// flush truncates the file back to its last synced size, so that files never have partial
+    /// lines, and discarded lines are never written, the following ones going to a new file.
+    pub struct FileStreamConsumer<'a> {
+        directory: PathBuf,
+        prefix: &'a str,
+        encoding: FilePayloadEncoding,
+        max_file_size: Option<u64>,
+        max_file_age: Option<Duration>,
+        current: Option<CurrentFile>
+    }
+
+    impl <'a> FileStreamConsumer<'a> {
+        /// Returns a new File Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * directory - the directory of the files, created when missing
+        /// * prefix - the prefix of the file names
+        /// * encoding - how payloads are written
+        /// * max_file_size - the size in bytes after which files are rotated, if any
+        /// * max_file_age - the time after which files are rotated, if any
+        pub fn new(directory: &str, prefix: &'a str, encoding: FilePayloadEncoding, max_file_size: Option<u64>, max_file_age: Option<Duration>) -> Result<FileStreamConsumer<'a>, Box<error::Error>> {
+            info!(target: "file", "Creating file consumer in {:?}, prefix {:?}", directory, prefix);
+            fs::create_dir_all(directory)?;
+            Ok(FileStreamConsumer{directory: PathBuf::from(directory), prefix, encoding, max_file_size, max_file_age, current: None})
+        }
+
+        /// Returns a new file, never overwriting existing ones
+        fn create_file(&self) -> Result<CurrentFile, Box<error::Error>> {
+            let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+            let mut sequence = 0;
+            loop {
+                let name = match sequence {
+                    0 => format!("{}-{}.jsonl", self.prefix, timestamp),
+                    x => format!("{}-{}-{}.jsonl", self.prefix, timestamp, x)
+                };
+                let path = self.directory.join(name);
+                match fs::OpenOptions::new().append(true).create_new(true).open(&path) {
+                    Ok(x) => {
+                        info!(target: "file", "Writing to {:?}", path);
+                        return Ok(CurrentFile{file: x, pending: Vec::new(), synced: 0, created: Instant::now()});
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                        sequence += 1;
+                    },
+                    Err(e) => {
+                        return Err(Box::new(e));
+                    }
+                }
+            }
+        }
+
+        /// Returns whether the current file must be rotated before writing more lines
+        fn must_rotate(&self, current: &CurrentFile) -> bool {
+            self.max_file_size.map_or(false, |x| current.synced + current.pending.len() as u64 >= x) ||
+                self.max_file_age.map_or(false, |x| current.created.elapsed() >= x)
+        }
+    }
+
+    /// Returns the JSON line of a message, without the newline
+    pub(crate) fn json_line(element: &common::SourceElement, encoding: &FilePayloadEncoding, timestamp: &str) -> Result<Vec<u8>, Box<error::Error>> {
+        let mut headers = Map::new();
+        for (name, value) in element.headers.iter() {
+            headers.insert(name.to_string(), Value::String(value.to_string()));
+        }
+        let mut line = json!({
+            "id": &*element.id,
+            "key": element.key.as_ref().map(|x| &**x),
+            "topic": element.topic.as_ref().map(|x| &**x),
+            "headers": headers,
+            "timestamp": timestamp
+        });
+        let payload = match (element.data.as_ref(), encoding) {
+            (None, _) => Value::Null,
+            (Some(x), FilePayloadEncoding::String) if std::str::from_utf8(x).is_ok() => Value::String(String::from_utf8_lossy(x).to_string()),
+            (Some(x), _) => {
+                line["payload_encoding"] = Value::String("base64".to_string());
+                Value::String(base64::encode(x))
+            }
+        };
+        line["payload"] = payload;
+        Ok(serde_json::to_vec(&line)?)
+    }
+
+    impl <'a> common::StreamConsumer for FileStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let rotate = self.current.as_ref().map_or(false, |x| self.must_rotate(x));
+            if rotate {
+                self.flush()?;
+                self.current = None;
+            }
+            if self.current.is_none() {
+                self.current = Some(self.create_file()?);
+            }
+            let line = json_line(&element, &self.encoding, &chrono::Utc::now().to_rfc3339())?;
+            let current = self.current.as_mut().unwrap();
+            current.pending.extend_from_slice(&line);
+            current.pending.push(b'\n');
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            if let Some(current) = self.current.as_mut() {
+                if let Err(e) = current.file.write_all(&current.pending).and_then(|_| current.file.sync_data()) {
+                    // The lines are written again by the next flush, unless discarded
+                    current.file.set_len(current.synced)?;
+                    return Err(Box::new(e));
+                }
+                current.synced += current.pending.len() as u64;
+                current.pending.clear();
+                debug!(target: "file", "Synced {} bytes", current.synced);
+            }
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            if let Some(current) = self.current.take() {
+                debug!(target: "file", "Discarding {} bytes", current.pending.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use serde_json::{json, Value};
+    use super::stream::*;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn lines_are_written_as_json() {
+        let mut element = source_element("1", "a payload");
+        element.key = Some(Box::from("a key"));
+        element.headers.push((Box::from("h"), Box::from("v")));
+        let line: Value = serde_json::from_slice(&json_line(&element, &FilePayloadEncoding::String, "2019-08-01T10:00:00+00:00").unwrap()).unwrap();
+        assert_eq!(
+            json!({"id": "1", "key": "a key", "topic": null, "headers": {"h": "v"}, "payload": "a payload", "timestamp": "2019-08-01T10:00:00+00:00"}),
+            line
+        );
+        element.data = Some(Box::from(&[0xffu8, 0x00][..]));
+        let line: Value = serde_json::from_slice(&json_line(&element, &FilePayloadEncoding::String, "").unwrap()).unwrap();
+        assert_eq!(json!("/wA="), line["payload"]);
+        assert_eq!(json!("base64"), line["payload_encoding"]);
+    }
+
+    #[test]
+    fn files_are_rotated_by_size() {
+        let directory = std::env::temp_dir().join(format!("pte-file-test-{}", rand::random::<u64>()));
+        let mut consumer = FileStreamConsumer::new(directory.to_str().unwrap(), "events", FilePayloadEncoding::Base64, Some(1), None).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.flush().unwrap();
+        let mut files: Vec<String> = fs::read_dir(&directory).unwrap()
+            .map(|x| fs::read_to_string(x.unwrap().path()).unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(2, files.len());
+        for (file, payload) in files.iter().zip(vec!["YQ==", "Yg=="]) {
+            assert_eq!(1, file.lines().count());
+            let line: Value = serde_json::from_str(file.trim_end()).unwrap();
+            assert_eq!(json!(payload), line["payload"]);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn discarded_lines_are_not_written() {
+        let directory = std::env::temp_dir().join(format!("pte-file-test-{}", rand::random::<u64>()));
+        let mut consumer = FileStreamConsumer::new(directory.to_str().unwrap(), "events", FilePayloadEncoding::String, None, None).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.flush().unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.discard();
+        consumer.write(source_element("3", "c")).unwrap();
+        consumer.flush().unwrap();
+        let mut payloads: Vec<Value> = fs::read_dir(&directory).unwrap()
+            .flat_map(|x| fs::read_to_string(x.unwrap().path()).unwrap().lines().map(|y| serde_json::from_str::<Value>(y).unwrap()["payload"].clone()).collect::<Vec<_>>())
+            .collect();
+        payloads.sort_by_key(|x| x.to_string());
+        assert_eq!(vec![json!("a"), json!("c")], payloads);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,11 +1,37 @@
 pub mod stream {
-    extern crate kafka;
+    extern crate rdkafka;
     use std::error;
-    use kafka::producer::{Producer, Record, RequiredAcks};
-    // use kafka::error::Error as KafkaError;
+    use std::sync::{Arc, Mutex};
+    use rdkafka::ClientContext;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use rdkafka::message::OwnedHeaders;
+    use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
     use std::time::Duration;
     use crate::common;
-    use log::{info, debug};
+    use log::{info, debug, warn, error};
+
+    /// The time a flush waits for all the messages to be acknowledged
+    const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Collects the delivery failures reported by the Kafka producer
+    struct DeliveryContext {
+        failures: Arc<Mutex<Vec<String>>>
+    }
+
+    impl ClientContext for DeliveryContext {}
+
+    impl ProducerContext for DeliveryContext {
+        type DeliveryOpaque = ();
+
+        fn delivery(&self, delivery_result: &DeliveryResult, _: Self::DeliveryOpaque) {
+            if let Err((e, _)) = delivery_result {
+                error!(target: "kafka", "Could not deliver message: {:?}", e);
+                self.failures.lock().unwrap().push(e.to_string());
+            }
+        }
+    }
+
     /// A stream consumer for Kafka
     pub struct KafkaStreamConsumer<'a> {
         /// The buffer of messages that will be sent in batch
@@ -15,7 +41,9 @@ pub mod stream {
         /// The topic where messages are sent
         topic: &'a str,
         /// Kafka messages producer
-        producer: Producer
+        producer: BaseProducer<DeliveryContext>,
+        /// The delivery failures since the last flush
+        failures: Arc<Mutex<Vec<String>>>
     }
 
     impl <'a> KafkaStreamConsumer<'a> {
@@ -24,22 +52,38 @@ pub mod stream {
         /// # Arguments
         ///
         /// * brokers - the list of Kafka hosts
-        /// * topic - the topic where message are sent
+        /// * topic - the topic where message are sent, unless messages specify their own
         /// * max_buffer_size - the max number of messages that are sent in batch
         ///
         pub fn new(brokers: Vec<String>, topic: &'a str, max_buffer_size: usize) -> Result<KafkaStreamConsumer, Box<error::Error>> {
             info!(target: "kafka", "Connecting to brokers {:?}, topic {:?}, max_buffer_size {:?}", brokers, topic, max_buffer_size);
+            let failures = Arc::new(Mutex::new(Vec::new()));
             Ok(KafkaStreamConsumer{
                 buffer: Vec::new(),
                 max_buffer_size,
                 topic,
-                producer: 
-                    Producer::from_hosts(brokers)
-                        .with_ack_timeout(Duration::from_secs(1))
-                        .with_required_acks(RequiredAcks::One)
-                        .create()?
+                producer:
+                    ClientConfig::new()
+                        .set("bootstrap.servers", &brokers.join(","))
+                        .set("acks", "1")
+                        .create_with_context(DeliveryContext{failures: Arc::clone(&failures)})?,
+                failures
             })
         }
+
+        /// Waits for the enqueued messages to be acknowledged, failing if some of them could not be
+        /// delivered or are still in flight after the timeout
+        fn await_delivery(&self) -> Result<(), Box<error::Error>> {
+            self.producer.flush(FLUSH_TIMEOUT);
+            let failures : Vec<String> = self.failures.lock().unwrap().drain(..).collect();
+            if self.producer.in_flight_count() > 0 {
+                return Err(Box::from(format!("{} Kafka messages not acknowledged", self.producer.in_flight_count())));
+            }
+            if !failures.is_empty() {
+                return Err(Box::from(format!("Could not deliver Kafka messages: {}", failures.join(", "))));
+            }
+            Ok(())
+        }
     }
 
     impl <'a> common::StreamConsumer for KafkaStreamConsumer<'a> {
@@ -55,12 +99,45 @@ pub mod stream {
 
         fn flush(&mut self) -> Result<(), Box<error::Error>> {
             info!("Flushing Kafka buffer, size: {:?}", self.buffer.len());
-            self.producer.send_all(
-                &self.buffer.iter().map(
-                    |x| Record {topic: self.topic, partition: -1, key: &*x.id, value: &*x.data}
-                ).collect::<Vec<Record<'_, &str, &[u8]>>>())?;
+            for x in self.buffer.iter() {
+                let mut headers = OwnedHeaders::new_with_capacity(x.headers.len());
+                for (name, value) in x.headers.iter() {
+                    headers = headers.add(name, &**value);
+                }
+                // A record with no payload is a tombstone
+                let mut record = BaseRecord::to(x.topic.as_ref().map_or(self.topic, |t| &**t))
+                    .key(&**x.key.as_ref().unwrap_or(&x.id))
+                    .headers(headers);
+                if let Some(data) = &x.data {
+                    record = record.payload(&**data);
+                }
+                loop {
+                    match self.producer.send(record) {
+                        Ok(_) => break,
+                        Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                            debug!(target: "kafka", "Producer queue is full, waiting");
+                            self.producer.poll(Duration::from_millis(100));
+                            record = r;
+                        },
+                        Err((e, _)) => {
+                            // The messages already enqueued are awaited, so that none of them is
+                            // delivered after the failed flush, interleaved with the retried ones
+                            if let Err(x) = self.await_delivery() {
+                                warn!(target: "kafka", "Enqueued messages of the failed flush: {}", x);
+                            }
+                            return Err(Box::new(e));
+                        }
+                    }
+                }
+            }
+            self.await_delivery()?;
             self.buffer.clear();
             return Ok(());
         }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+            self.failures.lock().unwrap().clear();
+        }
     }
 }
@@ -0,0 +1,187 @@
+pub mod stream {
+    extern crate base64;
+    extern crate ureq;
+    use std::collections::HashSet;
+    use std::error;
+    use std::thread;
+    use std::time::Duration;
+    use std::boxed::Box;
+    use serde_json::{json, Value};
+    use crate::common;
+    use crate::aws::signing::{AwsCredentials, json_request};
+    use log::{info, debug, warn};
+
+    /// The largest number of records of a PutRecords request
+    const MAX_BATCH_SIZE: usize = 500;
+
+    /// How many times the failed records of a batch are sent again
+    const MAX_RETRIES: u32 = 5;
+
+    /// The delay before the first retry, doubled at every further retry
+    const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+    /// A stream consumer putting messages to a Kinesis compatible data stream, e.g. AWS Kinesis or
+    /// LocalStack, with PutRecords requests of the JSON protocol. Every flush sends the buffered
+    /// messages, and succeeds only once every record has been put.
+    ///
+    /// The partition key is the message key, or the id when there is no key. Failed records, e.g.
+    /// because of throttling, are sent again with an exponential backoff. The messages of a key keep
+    /// their order, since a request has at most one record of every partition key and the next
+    /// record of a key is sent only once the previous one has been put. Kinesis records have no
+    /// attributes, so headers are not sent.
+    pub struct KinesisStreamConsumer<'a> {
+        agent: ureq::Agent,
+        endpoint: &'a str,
+        stream_name: &'a str,
+        region: &'a str,
+        credentials: &'a AwsCredentials,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> KinesisStreamConsumer<'a> {
+        /// Returns a new Kinesis Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * endpoint - the endpoint URL, e.g. `https://kinesis.eu-west-1.amazonaws.com`
+        /// * stream_name - the data stream
+        /// * region - the region the requests are signed for
+        /// * credentials - the credentials the requests are signed with
+        pub fn new(endpoint: &'a str, stream_name: &'a str, region: &'a str, credentials: &'a AwsCredentials) -> KinesisStreamConsumer<'a> {
+            info!(target: "kinesis", "Creating Kinesis consumer for stream {:?} at {:?}", stream_name, endpoint);
+            KinesisStreamConsumer{agent: ureq::Agent::new(), endpoint, stream_name, region, credentials, buffer: Vec::new()}
+        }
+
+        /// Puts some records, returning the indexes of the failed ones
+        fn put_records(&self, records: &[Value]) -> Result<Vec<usize>, Box<error::Error>> {
+            let request = json!({"StreamName": self.stream_name, "Records": records});
+            let response = json_request(
+                &self.agent, self.credentials, self.region, "kinesis", self.endpoint,
+                "Kinesis_20131202.PutRecords", "application/x-amz-json-1.1", &request
+            )?;
+            let results = match response["Records"].as_array() {
+                Some(x) if x.len() == records.len() => x,
+                _ => {
+                    return Err(Box::from("Kinesis returned an invalid PutRecords response"));
+                }
+            };
+            let mut failed = Vec::new();
+            for (i, result) in results.iter().enumerate() {
+                if !result["ErrorCode"].is_null() {
+                    warn!(target: "kinesis", "Kinesis failed to put record: {} {}", result["ErrorCode"], result["ErrorMessage"]);
+                    failed.push(i);
+                }
+            }
+            Ok(failed)
+        }
+    }
+
+    /// Returns the partition key of a message, i.e. its key or its id when there is no key
+    fn partition_key(element: &common::SourceElement) -> &str {
+        element.key.as_ref().map_or(&*element.id, |x| &**x)
+    }
+
+    /// Returns the PutRecords record of a message
+    pub(crate) fn kinesis_record(element: &common::SourceElement) -> Value {
+        json!({
+            "Data": base64::encode(element.data.as_ref().map_or(&[][..], |x| &**x)),
+            "PartitionKey": partition_key(element)
+        })
+    }
+
+    impl <'a> common::StreamConsumer for KinesisStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            let mut pending: Vec<&common::SourceElement> = self.buffer.iter().collect();
+            let mut retries = 0;
+            while !pending.is_empty() {
+                let batch = common::first_of_every_key(&pending, partition_key, MAX_BATCH_SIZE);
+                let records: Vec<Value> = batch.iter().map(|i| kinesis_record(pending[*i])).collect();
+                let failed = self.put_records(&records)?;
+                let put: HashSet<usize> = batch.iter().enumerate().filter(|(i, _)| !failed.contains(i)).map(|(_, x)| *x).collect();
+                pending = pending.into_iter().enumerate().filter(|(i, _)| !put.contains(i)).map(|(_, x)| x).collect();
+                if failed.is_empty() {
+                    retries = 0;
+                    continue;
+                }
+                if retries >= MAX_RETRIES {
+                    return Err(Box::from(format!("Kinesis failed to put {} records", failed.len())));
+                }
+                thread::sleep(RETRY_BACKOFF * 2u32.pow(retries));
+                retries += 1;
+            }
+            debug!(target: "kinesis", "Put {} elements", self.buffer.len());
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::stream::*;
+    use crate::aws::signing::AwsCredentials;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, http_stand_in};
+
+    #[test]
+    fn failed_records_are_put_again() {
+        let (url, requests) = http_stand_in(vec![
+            (200, r#"{"FailedRecordCount": 1, "Records": [{"SequenceNumber": "1", "ShardId": "shardId-0"}, {"ErrorCode": "ProvisionedThroughputExceededException", "ErrorMessage": "Rate exceeded"}]}"#),
+            (200, r#"{"FailedRecordCount": 0, "Records": [{"SequenceNumber": "2", "ShardId": "shardId-0"}]}"#)
+        ]);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = KinesisStreamConsumer::new(&url, "events", "us-east-1", &credentials);
+        let mut element = source_element("1", "a");
+        element.key = Some(Box::from("customer-1"));
+        consumer.write(element).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        let first: Value = serde_json::from_str(&requests[0].1).unwrap();
+        let second: Value = serde_json::from_str(&requests[1].1).unwrap();
+        assert_eq!(
+            json!({"StreamName": "events", "Records": [{"Data": "YQ==", "PartitionKey": "customer-1"}, {"Data": "Yg==", "PartitionKey": "2"}]}),
+            first
+        );
+        assert_eq!(json!([{"Data": "Yg==", "PartitionKey": "2"}]), second["Records"]);
+    }
+
+    #[test]
+    fn records_of_a_key_are_put_in_order() {
+        let (url, requests) = http_stand_in(vec![
+            (200, r#"{"FailedRecordCount": 1, "Records": [{"ErrorCode": "InternalFailure", "ErrorMessage": "Failure"}, {"SequenceNumber": "1", "ShardId": "shardId-0"}]}"#),
+            (200, r#"{"FailedRecordCount": 0, "Records": [{"SequenceNumber": "2", "ShardId": "shardId-0"}]}"#),
+            (200, r#"{"FailedRecordCount": 0, "Records": [{"SequenceNumber": "3", "ShardId": "shardId-0"}]}"#)
+        ]);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = KinesisStreamConsumer::new(&url, "events", "us-east-1", &credentials);
+        for (id, data) in [("1", "a"), ("2", "b")].iter() {
+            let mut element = source_element(id, data);
+            element.key = Some(Box::from("customer-1"));
+            consumer.write(element).unwrap();
+        }
+        consumer.write(source_element("3", "c")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        let records: Vec<Value> = requests.iter().map(|x| serde_json::from_str::<Value>(&x.1).unwrap()["Records"].clone()).collect();
+        assert_eq!(
+            vec![
+                json!([{"Data": "YQ==", "PartitionKey": "customer-1"}, {"Data": "Yw==", "PartitionKey": "3"}]),
+                json!([{"Data": "YQ==", "PartitionKey": "customer-1"}]),
+                json!([{"Data": "Yg==", "PartitionKey": "customer-1"}])
+            ],
+            records
+        );
+    }
+}
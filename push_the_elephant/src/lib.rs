@@ -3,11 +3,65 @@ mod kafka;
 mod pgsql;
 mod worker_builder;
 mod conf;
+mod cdc;
+mod schema;
+mod avro;
+mod dead_letter;
+mod protobuf;
+mod cloud_events;
+mod validation;
+mod quarantine;
+mod transform;
+mod scripting;
+mod encryption;
+mod masking;
+mod tee;
+mod sink;
+mod redis_streams;
+mod amqp;
+mod nats_jetstream;
+mod webhook;
+mod mqtt;
+mod file;
+mod aws;
+mod s3;
+mod relay;
+mod sqs;
+mod kinesis;
+mod pubsub;
+mod push_server;
 pub use crate::kafka::stream::*;
 pub use crate::common::*;
 pub use crate::worker_builder::*;
 pub use pgsql::stream::*;
 pub use crate::conf::configuration::*;
+pub use crate::cdc::triggers::*;
+pub use crate::schema::outbox::*;
+pub use crate::avro::stream::*;
+pub use crate::dead_letter::stream::*;
+pub use crate::protobuf::stream::*;
+pub use crate::cloud_events::format::*;
+pub use crate::validation::stream::*;
+pub use crate::quarantine::stream::*;
+pub use crate::transform::pipeline::*;
+pub use crate::scripting::transform::*;
+pub use crate::encryption::transform::*;
+pub use crate::masking::transform::*;
+pub use crate::tee::stream::*;
+pub use crate::sink::configuration::*;
+pub use crate::redis_streams::stream::*;
+pub use crate::amqp::stream::*;
+pub use crate::nats_jetstream::stream::*;
+pub use crate::webhook::stream::*;
+pub use crate::mqtt::stream::*;
+pub use crate::file::stream::*;
+pub use crate::aws::signing::*;
+pub use crate::s3::stream::*;
+pub use crate::relay::stream::*;
+pub use crate::sqs::stream::*;
+pub use crate::kinesis::stream::*;
+pub use crate::pubsub::stream::*;
+pub use crate::push_server::stream::*;
 
 #[macro_use]
 extern crate derive_builder;
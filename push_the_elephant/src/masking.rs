@@ -0,0 +1,217 @@
+pub mod transform {
+    extern crate sha2;
+    use std::error;
+    use std::fmt;
+    use std::str::FromStr;
+    use std::boxed::Box;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use crate::common;
+    use crate::transform::pipeline::{Transform, derived_id};
+    use log::debug;
+
+    /// How a JSON field is masked
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum MaskMethod {
+        /// Replaces the field with the hex SHA-256 digest of the salt and the field, so that equal
+        /// values are still equal once masked
+        Hash,
+        /// Keeps the first characters of the field
+        Truncate(usize),
+        /// Replaces the field with `null`
+        Null
+    }
+
+    #[derive(Debug)]
+    pub struct MaskMethodError {
+        method: String
+    }
+
+    impl error::Error for MaskMethodError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for MaskMethodError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid mask method {:?}", self.method)
+        }
+    }
+
+    impl FromStr for MaskMethod {
+        type Err = MaskMethodError;
+
+        /// Parses `hash`, `null` or `truncate:<length>`
+        fn from_str(s: &str) -> Result<MaskMethod, MaskMethodError> {
+            match s {
+                "hash" => Ok(MaskMethod::Hash),
+                "null" => Ok(MaskMethod::Null),
+                _ if s.starts_with("truncate:") => s["truncate:".len()..].parse()
+                    .map(MaskMethod::Truncate)
+                    .map_err(|_| MaskMethodError{method: s.to_string()}),
+                _ => Err(MaskMethodError{method: s.to_string()})
+            }
+        }
+    }
+
+    /// A [Transform](trait.Transform.html) masking JSON fields of the payloads.
+    ///
+    /// Fields are JSON pointers, where a `*` segment stands for every item of an array or every
+    /// property of an object, e.g. `/items/*/email`. Missing fields are ignored.
+    /// When copy topics are configured, the messages are sent unchanged and a masked copy is sent to
+    /// every copy topic, e.g. so that analytics topics get redacted data while operational topics
+    /// keep full data. Otherwise messages are masked in place. The id of every copy is
+    /// [derived](fn.derived_id.html) from the message id and the copy topic, e.g. `42#mask-analytics`.
+    /// Absent payloads, e.g. tombstones, are not masked.
+    #[derive(Debug)]
+    pub struct MaskTransform {
+        /// The masked fields and how they are masked
+        pub fields: Vec<(String, MaskMethod)>,
+        /// The salt of the hashed fields
+        pub salt: String,
+        /// The topics of the masked copies
+        pub topics: Vec<String>
+    }
+
+    /// Returns a field masked with the specified method
+    fn mask_value(value: &Value, method: &MaskMethod, salt: &str) -> Value {
+        let text = match value {
+            Value::Null => {
+                return Value::Null;
+            },
+            Value::String(x) => x.clone(),
+            x => x.to_string()
+        };
+        match method {
+            MaskMethod::Hash => {
+                let mut hasher = Sha256::new();
+                hasher.update(salt.as_bytes());
+                hasher.update(text.as_bytes());
+                Value::String(hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect())
+            },
+            MaskMethod::Truncate(length) => Value::String(text.chars().take(*length).collect()),
+            MaskMethod::Null => Value::Null
+        }
+    }
+
+    /// Masks the fields matching the JSON pointer segments
+    fn mask_path(value: &mut Value, segments: &[String], method: &MaskMethod, salt: &str) {
+        let (segment, rest) = match segments.split_first() {
+            Some(x) => x,
+            None => {
+                *value = mask_value(value, method, salt);
+                return;
+            }
+        };
+        match value {
+            Value::Object(x) if segment == "*" => {
+                for v in x.values_mut() {
+                    mask_path(v, rest, method, salt);
+                }
+            },
+            Value::Array(x) if segment == "*" => {
+                for v in x.iter_mut() {
+                    mask_path(v, rest, method, salt);
+                }
+            },
+            Value::Object(x) => {
+                if let Some(v) = x.get_mut(segment) {
+                    mask_path(v, rest, method, salt);
+                }
+            },
+            Value::Array(x) => {
+                if let Some(v) = segment.parse::<usize>().ok().and_then(|i| x.get_mut(i)) {
+                    mask_path(v, rest, method, salt);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    impl MaskTransform {
+        /// Returns a payload with the configured fields masked
+        pub fn mask(&self, data: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+            let mut payload: Value = serde_json::from_slice(data)?;
+            for (path, method) in self.fields.iter() {
+                let segments: Vec<String> = path.split('/').skip(1).map(|x| x.replace("~1", "/").replace("~0", "~")).collect();
+                mask_path(&mut payload, &segments, method, &self.salt);
+            }
+            Ok(serde_json::to_vec(&payload)?)
+        }
+    }
+
+    impl Transform for MaskTransform {
+        fn apply(&self, mut element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            let masked = match element.data.as_ref() {
+                Some(x) => self.mask(x)?.into_boxed_slice(),
+                None => {
+                    return Ok(vec![element]);
+                }
+            };
+            debug!(target: "masking", "Masked element {:?}", element.id);
+            if self.topics.is_empty() {
+                element.data = Some(masked);
+                return Ok(vec![element]);
+            }
+            let mut result = Vec::new();
+            for topic in self.topics.iter() {
+                result.push(common::SourceElement{
+                    id: derived_id(&element.id, "mask", topic),
+                    key: element.key.clone(),
+                    data: Some(masked.clone()),
+                    headers: element.headers.clone(),
+                    topic: Some(Box::from(topic.as_str()))
+                });
+            }
+            result.insert(0, element);
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::transform::{MaskMethod, MaskTransform};
+    use crate::common::tests::source_element;
+    use crate::transform::pipeline::Transform;
+
+    fn mask_transform(topics: Vec<String>) -> MaskTransform {
+        MaskTransform{
+            fields: vec![
+                ("/email".to_string(), "hash".parse().unwrap()),
+                ("/phone".to_string(), "truncate:3".parse().unwrap()),
+                ("/items/*/name".to_string(), MaskMethod::Null)
+            ],
+            salt: "salt".to_string(),
+            topics
+        }
+    }
+
+    #[test]
+    fn fields_are_masked() {
+        let result = mask_transform(vec![]).apply(
+            source_element("1", r#"{"email": "a@b.c", "phone": "+39123456", "items": [{"name": "a", "qty": 1}, {"name": "b"}]}"#)
+        ).unwrap();
+        assert_eq!(1, result.len());
+        let payload: Value = serde_json::from_slice(result[0].data.as_ref().unwrap()).unwrap();
+        assert_eq!(json!({
+            "email": "64e00aeee03713efc7b9a3eb44b91f14610af815d66b43de91beeff6fb5a11ff",
+            "phone": "+39",
+            "items": [{"name": null, "qty": 1}, {"name": null}]
+        }), payload);
+    }
+
+    #[test]
+    fn masked_copies_are_sent_to_the_copy_topics() {
+        let result = mask_transform(vec!["analytics".to_string()]).apply(source_element("1", r#"{"phone": "+39123456"}"#)).unwrap();
+        assert_eq!(2, result.len());
+        assert_eq!(None, result[0].topic);
+        assert_eq!(Some(Box::from(&br#"{"phone": "+39123456"}"#[..])), result[0].data);
+        assert_eq!("1", &*result[0].id);
+        assert_eq!("1#mask-analytics", &*result[1].id);
+        assert_eq!(Some(Box::from("analytics")), result[1].topic);
+        assert_eq!(Some(Box::from(&br#"{"phone":"+39"}"#[..])), result[1].data);
+    }
+}
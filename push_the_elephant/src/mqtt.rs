@@ -0,0 +1,380 @@
+pub mod stream {
+    extern crate rumqttc;
+    use std::collections::HashSet;
+    use std::error;
+    use std::fmt;
+    use std::thread;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use std::boxed::Box;
+    use rumqttc::{Client, Connection, Event, MqttOptions, Outgoing, Packet, QoS, RecvTimeoutError};
+    use rumqttc::v5;
+    use rumqttc::v5::mqttbytes::v5::{ConnectProperties, PubAckReason, PubRecReason, PublishProperties};
+    use crate::common;
+    use log::{info, debug, warn};
+
+    /// The time waited before reconnecting after a connection error
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    /// The MQTT protocol version the messages are published with
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum MqttVersion {
+        /// MQTT 3.1.1, whose messages have no headers
+        V311,
+        /// MQTT 5, where headers are sent as user properties
+        V5
+    }
+
+    #[derive(Debug)]
+    pub struct MqttVersionError {
+        version: String
+    }
+
+    impl error::Error for MqttVersionError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for MqttVersionError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid MQTT version {:?}", self.version)
+        }
+    }
+
+    impl FromStr for MqttVersion {
+        type Err = MqttVersionError;
+
+        fn from_str(s: &str) -> Result<MqttVersion, MqttVersionError> {
+            match s {
+                "3.1.1" => Ok(MqttVersion::V311),
+                "5" => Ok(MqttVersion::V5),
+                _ => Err(MqttVersionError{version: s.to_string()})
+            }
+        }
+    }
+
+    /// The client and the connection of the configured protocol version
+    enum MqttClient {
+        V311(Client, Connection),
+        V5(v5::Client, v5::Connection)
+    }
+
+    /// What the connection returned while waiting for acknowledgements
+    enum Received {
+        /// A message has been handed to the broker with the specified packet id
+        Published(u16),
+        /// A message has been acknowledged with a PUBACK or a PUBCOMP
+        Acknowledged(u16),
+        /// A message has been rejected by the broker with a PUBACK or a PUBREC
+        Rejected(u16, String),
+        /// Any other event
+        Other,
+        /// The connection failed, and will be established again at the next poll
+        Failed(String),
+        /// No event arrived in time
+        Timeout,
+        /// The connection has been closed
+        Closed
+    }
+
+    /// Returns the MQTT 5 quality of service matching an MQTT 3.1.1 one
+    fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+        match qos {
+            QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+            QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce
+        }
+    }
+
+    /// Returns the MQTT 5 publish properties of a message, i.e. its headers as user properties
+    pub(crate) fn publish_properties(element: &common::SourceElement) -> PublishProperties {
+        PublishProperties{
+            user_properties: element.headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..PublishProperties::default()
+        }
+    }
+
+    impl MqttClient {
+        fn publish(&self, element: &common::SourceElement, topic: String, qos: QoS) -> Result<(), Box<error::Error>> {
+            let data: Vec<u8> = element.data.as_ref().map_or(Vec::new(), |x| x.to_vec());
+            match self {
+                MqttClient::V311(client, _) => client.publish(topic, qos, false, data)?,
+                MqttClient::V5(client, _) => client.publish_with_properties(topic, v5_qos(qos), false, data, publish_properties(element))?
+            }
+            Ok(())
+        }
+
+        fn recv_timeout(&mut self, timeout: Duration, qos: QoS) -> Received {
+            match self {
+                MqttClient::V311(_, connection) => match connection.recv_timeout(timeout) {
+                    Ok(Ok(Event::Outgoing(Outgoing::Publish(x)))) => Received::Published(x),
+                    Ok(Ok(Event::Incoming(Packet::PubAck(x)))) if qos == QoS::AtLeastOnce => Received::Acknowledged(x.pkid),
+                    Ok(Ok(Event::Incoming(Packet::PubComp(x)))) if qos == QoS::ExactlyOnce => Received::Acknowledged(x.pkid),
+                    Ok(Ok(_)) => Received::Other,
+                    Ok(Err(e)) => Received::Failed(e.to_string()),
+                    Err(RecvTimeoutError::Timeout) => Received::Timeout,
+                    Err(RecvTimeoutError::Disconnected) => Received::Closed
+                },
+                MqttClient::V5(_, connection) => {
+                    let started = Instant::now();
+                    match connection.recv_timeout(timeout) {
+                        Ok(Ok(v5::Event::Outgoing(Outgoing::Publish(x)))) => Received::Published(x),
+                        Ok(Ok(v5::Event::Incoming(v5::Incoming::PubAck(x)))) if qos == QoS::AtLeastOnce => match x.reason {
+                            PubAckReason::Success | PubAckReason::NoMatchingSubscribers => Received::Acknowledged(x.pkid),
+                            reason => Received::Rejected(x.pkid, format!("Message {} rejected: {:?}", x.pkid, reason))
+                        },
+                        // A PUBREC with a failure reason ends the exactly once flow, no PUBCOMP follows
+                        Ok(Ok(v5::Event::Incoming(v5::Incoming::PubRec(x)))) if qos == QoS::ExactlyOnce => match x.reason {
+                            PubRecReason::Success | PubRecReason::NoMatchingSubscribers => Received::Other,
+                            reason => Received::Rejected(x.pkid, format!("Message {} rejected: {:?}", x.pkid, reason))
+                        },
+                        Ok(Ok(v5::Event::Incoming(v5::Incoming::PubComp(x)))) if qos == QoS::ExactlyOnce => Received::Acknowledged(x.pkid),
+                        Ok(Ok(_)) => Received::Other,
+                        Ok(Err(e)) => Received::Failed(e.to_string()),
+                        // The MQTT 5 receive errors are not exported, so timeouts are told apart by the elapsed time
+                        Err(_) if started.elapsed() >= timeout => Received::Timeout,
+                        Err(_) => Received::Closed
+                    }
+                }
+            }
+        }
+    }
+
+    /// The messages waiting for their acknowledgements, by packet id. Packet ids are assigned by
+    /// the connection, in the order the messages were published, and known once the messages
+    /// have been handed to the broker.
+    ///
+    /// The messages of a discarded batch are still sent again by the persistent session, so their
+    /// packet ids are kept apart, and their late acknowledgements are not counted for later batches.
+    #[derive(Debug, Default)]
+    pub(crate) struct InFlight {
+        /// The messages of the current batch not yet handed to the broker
+        unsent: usize,
+        /// The packet ids of the messages of the current batch
+        packet_ids: HashSet<u16>,
+        /// The messages of the discarded batches not yet handed to the broker
+        discarded_unsent: usize,
+        /// The packet ids of the messages of the discarded batches
+        discarded_packet_ids: HashSet<u16>
+    }
+
+    impl InFlight {
+        /// Returns the number of messages of the current batch waiting for their acknowledgements
+        pub(crate) fn pending(&self) -> usize {
+            self.unsent + self.packet_ids.len()
+        }
+
+        /// Counts a message published by the current batch
+        pub(crate) fn publish(&mut self) {
+            self.unsent += 1;
+        }
+
+        /// Records the packet id of a message handed to the broker. Messages sent again, e.g.
+        /// after a reconnection, are already known.
+        pub(crate) fn sent(&mut self, packet_id: u16) {
+            if self.packet_ids.contains(&packet_id) || self.discarded_packet_ids.contains(&packet_id) {
+                return;
+            }
+            if self.discarded_unsent > 0 {
+                self.discarded_unsent -= 1;
+                self.discarded_packet_ids.insert(packet_id);
+            } else if self.unsent > 0 {
+                self.unsent -= 1;
+                self.packet_ids.insert(packet_id);
+            }
+        }
+
+        /// Removes an acknowledged message, returning whether it belongs to the current batch
+        pub(crate) fn acknowledge(&mut self, packet_id: u16) -> bool {
+            self.discarded_packet_ids.remove(&packet_id);
+            self.packet_ids.remove(&packet_id)
+        }
+
+        /// Forgets the messages of the current batch, whose acknowledgements are ignored from now on
+        pub(crate) fn discard(&mut self) {
+            self.discarded_unsent += self.unsent;
+            self.unsent = 0;
+            self.discarded_packet_ids.extend(self.packet_ids.drain());
+        }
+    }
+
+    /// A stream consumer publishing messages to an MQTT 3.1.1 or MQTT 5 broker, e.g. Mosquitto,
+    /// with QoS 1 or 2, so that a flush succeeds only once the broker has acknowledged every
+    /// message with a PUBACK or a PUBCOMP.
+    ///
+    /// The MQTT topic is the message topic when set, otherwise the topic template, where `{key}`
+    /// and `{id}` are replaced by the message key and id. With MQTT 5 the headers are sent as user
+    /// properties, while MQTT 3.1.1 messages have no headers, so only the payloads are published.
+    pub struct MqttStreamConsumer<'a> {
+        client: MqttClient,
+        topic: &'a str,
+        qos: QoS,
+        ack_timeout: Duration,
+        in_flight: InFlight,
+        max_pending: usize
+    }
+
+    impl <'a> MqttStreamConsumer<'a> {
+        /// Returns a new MQTT Stream Consumer. The session is persistent, so that the broker keeps
+        /// the messages in flight across reconnections.
+        ///
+        /// # Arguments
+        ///
+        /// * host - the broker host
+        /// * port - the broker port, usually 1883
+        /// * client_id - the MQTT client id, which must be unique among the broker clients
+        /// * credentials - the user name and password, if any
+        /// * version - the MQTT protocol version
+        /// * topic - the topic template of the messages without topic, e.g. `devices/{key}/events`
+        /// * qos - the quality of service of the messages, either at least once or exactly once
+        /// * ack_timeout - the time a flush waits for the broker to acknowledge the messages
+        /// * max_pending - the number of messages after which the consumer waits for their acknowledgements
+        pub fn new(host: &str, port: u16, client_id: &str, credentials: Option<(&str, &str)>, version: MqttVersion, topic: &'a str, qos: QoS, ack_timeout: Duration, max_pending: usize) -> Result<MqttStreamConsumer<'a>, Box<error::Error>> {
+            if qos == QoS::AtMostOnce {
+                return Err(Box::from("MQTT messages cannot be acknowledged with QoS 0"));
+            }
+            info!(target: "mqtt", "Creating MQTT {:?} consumer for {}:{}, topic {:?}, {:?}", version, host, port, topic, qos);
+            let client = match version {
+                MqttVersion::V311 => {
+                    let mut options = MqttOptions::new(client_id, host, port);
+                    options.set_clean_session(false);
+                    if let Some((username, password)) = credentials {
+                        options.set_credentials(username, password);
+                    }
+                    let (client, connection) = Client::new(options, max_pending.max(1));
+                    MqttClient::V311(client, connection)
+                },
+                MqttVersion::V5 => {
+                    let mut options = v5::MqttOptions::new(client_id, host, port);
+                    options.set_clean_start(false);
+                    // MQTT 5 sessions end with the connection unless they have an expiry interval
+                    let mut properties = ConnectProperties::new();
+                    properties.session_expiry_interval = Some(u32::MAX);
+                    options.set_connect_properties(properties);
+                    if let Some((username, password)) = credentials {
+                        options.set_credentials(username, password);
+                    }
+                    let (client, connection) = v5::Client::new(options, max_pending.max(1));
+                    MqttClient::V5(client, connection)
+                }
+            };
+            Ok(MqttStreamConsumer{client, topic, qos, ack_timeout, in_flight: InFlight::default(), max_pending})
+        }
+    }
+
+    /// Returns the MQTT topic of a message
+    pub(crate) fn mqtt_topic(element: &common::SourceElement, topic: &str) -> String {
+        match element.topic.as_ref() {
+            Some(x) => x.to_string(),
+            None => topic
+                .replace("{key}", element.key.as_ref().map_or(&*element.id, |x| &**x))
+                .replace("{id}", &element.id)
+        }
+    }
+
+    impl <'a> common::StreamConsumer for MqttStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.client.publish(&element, mqtt_topic(&element, self.topic), self.qos)?;
+            self.in_flight.publish();
+            if self.in_flight.pending() >= self.max_pending {
+                return self.flush();
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            // The connection is driven here only, so the messages are sent while waiting for their acknowledgements
+            let deadline = Instant::now() + self.ack_timeout;
+            while self.in_flight.pending() > 0 {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match self.client.recv_timeout(timeout, self.qos) {
+                    Received::Published(x) => {
+                        self.in_flight.sent(x);
+                    },
+                    Received::Acknowledged(x) => {
+                        if self.in_flight.acknowledge(x) {
+                            debug!(target: "mqtt", "Message {} acknowledged", x);
+                        } else {
+                            debug!(target: "mqtt", "Message {} of a discarded batch acknowledged", x);
+                        }
+                    },
+                    Received::Other => (),
+                    Received::Rejected(x, e) => {
+                        if self.in_flight.acknowledge(x) {
+                            return Err(Box::from(e));
+                        }
+                        warn!(target: "mqtt", "{} in a discarded batch", e);
+                    },
+                    Received::Failed(e) => {
+                        // The next poll reconnects, and the messages in flight are sent again
+                        warn!(target: "mqtt", "MQTT connection error: {}", e);
+                        thread::sleep(RECONNECT_DELAY.min(deadline.saturating_duration_since(Instant::now())));
+                    },
+                    Received::Timeout => {
+                        return Err(Box::from(format!("{} messages have not been acknowledged", self.in_flight.pending())));
+                    },
+                    Received::Closed => {
+                        return Err(Box::from("The MQTT connection has been closed"));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.in_flight.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{mqtt_topic, publish_properties, InFlight, MqttVersion};
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn topics_are_derived_from_the_message() {
+        let mut element = source_element("1", "a");
+        assert_eq!("devices/1/events", mqtt_topic(&element, "devices/{key}/events"));
+        element.key = Some(Box::from("thermostat"));
+        assert_eq!("devices/thermostat/1", mqtt_topic(&element, "devices/{key}/{id}"));
+        element.topic = Some(Box::from("alerts"));
+        assert_eq!("alerts", mqtt_topic(&element, "devices/{key}/events"));
+    }
+
+    #[test]
+    fn headers_are_user_properties() {
+        let mut element = source_element("1", "a");
+        element.headers.push((Box::from("type"), Box::from("created")));
+        assert_eq!(vec![("type".to_string(), "created".to_string())], publish_properties(&element).user_properties);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(MqttVersion::V311, "3.1.1".parse().unwrap());
+        assert_eq!(MqttVersion::V5, "5".parse().unwrap());
+        assert!("4".parse::<MqttVersion>().is_err());
+    }
+
+    #[test]
+    fn late_acknowledgements_of_discarded_batches_are_ignored() {
+        let mut in_flight = InFlight::default();
+        in_flight.publish();
+        in_flight.publish();
+        in_flight.sent(1);
+        in_flight.discard();
+        assert_eq!(0, in_flight.pending());
+        in_flight.publish();
+        // The unsent message of the discarded batch goes first, then the new one, then the first is sent again
+        in_flight.sent(2);
+        in_flight.sent(3);
+        in_flight.sent(1);
+        assert_eq!(1, in_flight.pending());
+        assert!(!in_flight.acknowledge(1));
+        assert!(!in_flight.acknowledge(2));
+        assert_eq!(1, in_flight.pending());
+        assert!(in_flight.acknowledge(3));
+        assert_eq!(0, in_flight.pending());
+    }
+}
@@ -0,0 +1,134 @@
+pub mod stream {
+    extern crate nats;
+    use std::error;
+    use std::boxed::Box;
+    use nats::header::HeaderMap;
+    use nats::jetstream::JetStream;
+    use crate::common;
+    use log::{info, debug};
+
+    /// The header JetStream deduplicates messages by
+    pub const NATS_MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+    /// A stream consumer publishing messages to NATS JetStream. Buffered messages are published on
+    /// flush, which succeeds only once JetStream has acknowledged every message.
+    ///
+    /// The subject is the message topic when set, otherwise the subject template, where `{key}`
+    /// and `{id}` are replaced by the message key and id. The message id, prefixed with the name of
+    /// the source of the messages, is sent as the
+    /// [NATS_MSG_ID_HEADER](constant.NATS_MSG_ID_HEADER.html) header, so that messages published
+    /// twice, e.g. after a restart, are discarded by the server while messages of different
+    /// sources with the same id are not.
+    pub struct NatsStreamConsumer<'a> {
+        jetstream: JetStream,
+        subject: &'a str,
+        source: &'a str,
+        buffer: Vec<common::SourceElement>,
+        max_buffer_size: usize
+    }
+
+    impl <'a> NatsStreamConsumer<'a> {
+        /// Returns a new NATS Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * url - the NATS URL, e.g. `nats://localhost:4222`
+        /// * subject - the subject template of the messages without topic, e.g. `orders.{key}`
+        /// * source - the name of the source of the messages, e.g. the outbox table
+        /// * max_buffer_size - the number of messages after which they are published
+        pub fn new(url: &str, subject: &'a str, source: &'a str, max_buffer_size: usize) -> Result<NatsStreamConsumer<'a>, Box<error::Error>> {
+            info!(target: "nats", "Creating NATS JetStream consumer for subject {:?}", subject);
+            let jetstream = nats::jetstream::new(nats::connect(url)?);
+            Ok(NatsStreamConsumer{jetstream, subject, source, buffer: Vec::new(), max_buffer_size})
+        }
+    }
+
+    /// Returns the subject of a message
+    pub(crate) fn subject(element: &common::SourceElement, subject: &str) -> String {
+        match element.topic.as_ref() {
+            Some(x) => x.to_string(),
+            None => subject
+                .replace("{key}", element.key.as_ref().map_or(&*element.id, |x| &**x))
+                .replace("{id}", &element.id)
+        }
+    }
+
+    /// Returns the id JetStream deduplicates a message by, made of the source name and message id
+    pub(crate) fn message_id(source: &str, element: &common::SourceElement) -> String {
+        format!("{}:{}", source, element.id)
+    }
+
+    /// Returns the headers of a message as name and value pairs. The message id comes last and
+    /// replaces any row header of the same name, so that rows cannot defeat the deduplication.
+    pub(crate) fn headers<'b>(element: &'b common::SourceElement, message_id: &'b str) -> Vec<(&'b str, &'b str)> {
+        let mut headers: Vec<(&str, &str)> = element.headers.iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case(NATS_MSG_ID_HEADER))
+            .map(|(name, value)| (&**name, &**value))
+            .collect();
+        headers.push((NATS_MSG_ID_HEADER, message_id));
+        headers
+    }
+
+    impl <'a> common::StreamConsumer for NatsStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            if self.buffer.len() >= self.max_buffer_size {
+                return self.flush();
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            for element in self.buffer.iter() {
+                let id = message_id(self.source, element);
+                let mut headers = HeaderMap::new();
+                for (name, value) in self::headers(element, &id) {
+                    headers.insert(name, value);
+                }
+                let data: &[u8] = element.data.as_ref().map_or(&[][..], |x| &**x);
+                let message = nats::Message::new(&subject(element, self.subject), None, data, Some(headers));
+                let ack = self.jetstream.publish_message(&message)?;
+                debug!(target: "nats", "Element {:?} acknowledged at sequence {} of {:?}, duplicate: {}", element.id, ack.sequence, ack.stream, ack.duplicate);
+            }
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{subject, message_id, headers, NATS_MSG_ID_HEADER};
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn subjects_are_derived_from_the_message() {
+        let mut element = source_element("1", "a");
+        assert_eq!("orders.1", subject(&element, "orders.{key}"));
+        element.key = Some(Box::from("customer"));
+        assert_eq!("orders.customer.1", subject(&element, "orders.{key}.{id}"));
+        element.topic = Some(Box::from("invoices"));
+        assert_eq!("invoices", subject(&element, "orders.{key}"));
+    }
+
+    #[test]
+    fn message_id_header_comes_last_and_cannot_be_overridden() {
+        let mut element = source_element("1", "a");
+        element.headers = vec![
+            (Box::from("nats-msg-id"), Box::from("forged")),
+            (Box::from("origin"), Box::from("orders"))
+        ];
+        assert_eq!(vec![("origin", "orders"), (NATS_MSG_ID_HEADER, "outbox:1")], headers(&element, "outbox:1"));
+    }
+
+    #[test]
+    fn message_ids_are_prefixed_with_the_source() {
+        let element = source_element("1", "a");
+        assert_eq!("orders_outbox:1", message_id("orders_outbox", &element));
+        assert_ne!(message_id("orders_outbox", &element), message_id("invoices_outbox", &element));
+    }
+}
@@ -1,19 +1,80 @@
 pub mod stream {
     extern crate postgres;
     use crate::common;
+    use crate::cloud_events::format::CloudEventsColumns;
     use std::error;
+    use std::fmt;
+    use std::str::FromStr;
     use postgres::{Connection, TlsMode};
     use std::boxed::Box;
     use fallible_iterator::FallibleIterator;
-    use serde_json::{Value};
-    use std::time::{SystemTime, Duration}; 
+    use serde_json::{Map, Value};
+    use std::time::{SystemTime, Duration};
     use log::{info, debug, error};
-    
+
+    /// The prefix of the topics where Debezium outbox events are routed
+    pub const DEBEZIUM_TOPIC_PREFIX: &str = "outbox.event.";
+
+    /// The layout of the rows in the table where messages to be sent are kept
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum OutboxFormat {
+        /// The message content, key and headers are read from the configured columns
+        Plain,
+        /// The Debezium outbox event router layout, i.e. the `id`, `aggregatetype`, `aggregateid`,
+        /// `type` and `payload` columns. Messages are routed to the `outbox.event.<aggregatetype>`
+        /// topic, keyed by `aggregateid`, with the `id` and `type` headers
+        Debezium
+    }
+
+    #[derive(Debug)]
+    pub struct OutboxFormatError {
+        format: String
+    }
+
+    impl error::Error for OutboxFormatError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for OutboxFormatError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid outbox format {:?}", self.format)
+        }
+    }
+
+    impl FromStr for OutboxFormat {
+        type Err = OutboxFormatError;
+
+        fn from_str(s: &str) -> Result<OutboxFormat, OutboxFormatError> {
+            match s {
+                "plain" => Ok(OutboxFormat::Plain),
+                "debezium" => Ok(OutboxFormat::Debezium),
+                _ => Err(OutboxFormatError{format: s.to_string()})
+            }
+        }
+    }
+
+    /// What the message content of the [Plain](enum.OutboxFormat.html#variant.Plain) format is
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum PayloadMode {
+        /// The content of the configured column
+        Column,
+        /// The row serialized as a JSON object by PostGreSQL, restricted to the specified columns
+        /// unless empty. The restriction applies to the rows of notifications, too
+        Row(Vec<String>)
+    }
+
     /// A StreamProducer for PostGreSQL LISTEN/NOTIFY backed up by standard SQL
     pub struct PostgreSQLListenStreamProducer<'a> {
         url: &'a str,
         table_name: &'a str,
         column_name: &'a str,
+        payload_mode: PayloadMode,
+        key_column_name: Option<&'a str>,
+        headers_column_name: Option<&'a str>,
+        format: OutboxFormat,
+        cloud_events: Option<CloudEventsColumns<'a>>,
         channel: &'a str,
         notify_timeout_total: Duration,
         notify_timeout: Duration
@@ -27,57 +88,210 @@ pub mod stream {
         /// * `url` - PostGreSQL connection URL
         /// * `table_name` - The table where messages to be sent are kept
         /// * `column_name` - The column in the `table_name` table where the message content
-        /// resides
+        /// resides. A NULL value is sent as an absent payload (e.g. a Kafka tombstone)
+        /// * `payload_mode` - Whether the message content is the `column_name` column or the whole
+        /// row. In the latter case, notifications are expected to carry the row JSON as payload
+        /// * `key_column_name` - The optional column in the `table_name` table where the message
+        /// key resides
+        /// * `headers_column_name` - The optional column in the `table_name` table where the
+        /// message headers reside, as a JSON object
+        /// * `format` - The layout of the rows in the `table_name` table. The column arguments
+        /// are only used by the [Plain](enum.OutboxFormat.html#variant.Plain) format
+        /// * `cloud_events` - The columns of the CloudEvents attributes, sent as `ce_*` headers, if
+        /// any. They are expected in the notifications, too
+        /// * `channel` - The PostGreSQL channel where notifications are sent
         /// * `notify_timeout_total` - The timeout after which the producer moves data from
         /// PostGreSQL to Kafka by using a standard SQL query and then flushes it. After that, it
         /// starts back listening for notifications.
         /// * `notify_timeout` - The timeout after which the notification system times out. When
         /// this happens, the producer flushes all the data, then starts back listening for
         /// notifications.
-        pub fn new(url: &'a str, table_name: &'a str, column_name: &'a str, channel: &'a str, notify_timeout_total: Duration, notify_timeout: Duration) -> PostgreSQLListenStreamProducer<'a> {
-            info!(target: "postgres", "Creating PostGreSQL connector for table {:?}, notifications at channel {:?}", table_name, channel);
-            PostgreSQLListenStreamProducer{url, table_name, column_name, channel, notify_timeout_total, notify_timeout}
+        pub fn new(url: &'a str, table_name: &'a str, column_name: &'a str, payload_mode: PayloadMode, key_column_name: Option<&'a str>, headers_column_name: Option<&'a str>, format: OutboxFormat, cloud_events: Option<CloudEventsColumns<'a>>, channel: &'a str, notify_timeout_total: Duration, notify_timeout: Duration) -> PostgreSQLListenStreamProducer<'a> {
+            info!(target: "postgres", "Creating PostGreSQL connector for table {:?}, format {:?}, notifications at channel {:?}", table_name, format, channel);
+            PostgreSQLListenStreamProducer{url, table_name, column_name, payload_mode, key_column_name, headers_column_name, format, cloud_events, channel, notify_timeout_total, notify_timeout}
         }
     }
-    
+
     impl <'a> PostgreSQLListenStreamProducer<'a> {
-        fn flush_consumer(&self, data_to_delete: &mut Vec<i32>, consumer: &mut impl common::StreamConsumer, conn: &Connection) -> Result<(), Box<error::Error>> {
+        fn flush_consumer(&self, data_to_delete: &mut Vec<String>, consumer: &mut impl common::StreamConsumer, conn: &Connection, delete_sql: &str) -> Result<(), Box<error::Error>> {
             info!(target: "postgres", "Flushing consumer");
             consumer.flush()?;
             if !data_to_delete.is_empty() {
                 info!(target: "postgres", "Deleting {:?} rows from the table as already pushed", data_to_delete.len());
-                conn.execute(
-                    &format!(
-                        "DELETE FROM {} WHERE id IN ({})", 
-                        self.table_name, 
-                        data_to_delete.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",")
-                    ), 
-                    &[]
-                )?;
+                conn.execute(delete_sql, &[&*data_to_delete])?;
             }
             data_to_delete.clear();
             return Ok(());
         }
+
+        /// Returns the SQL query returning the rows of the table as JSON objects, having the same
+        /// fields as the notifications
+        pub(crate) fn fallback_sql(&self) -> String {
+            let (mut columns, selected) = match self.format {
+                OutboxFormat::Plain => (
+                    format!(
+                        "id, {} AS payload, {} AS key, {} AS headers",
+                        self.payload_sql(),
+                        self.key_column_name.unwrap_or("NULL::varchar"),
+                        self.headers_column_name.unwrap_or("NULL::jsonb")
+                    ),
+                    vec!["id", "payload", "key", "headers"]
+                ),
+                OutboxFormat::Debezium => (
+                    "id, aggregatetype, aggregateid, type, payload".to_string(),
+                    vec!["id", "aggregatetype", "aggregateid", "type", "payload"]
+                )
+            };
+            if let Some(x) = &self.cloud_events {
+                for column in x.columns().iter().filter(|c| !selected.contains(*c)) {
+                    columns.push_str(", ");
+                    columns.push_str(column);
+                }
+            }
+            format!("SELECT row_to_json(new_row)::text FROM (SELECT {} FROM {} outbox_row) new_row", columns, self.table_name)
+        }
+
+        /// Returns the SQL expression of the message content of the [Plain](enum.OutboxFormat.html#variant.Plain) format
+        fn payload_sql(&self) -> String {
+            match &self.payload_mode {
+                PayloadMode::Column => self.column_name.to_string(),
+                PayloadMode::Row(columns) if columns.is_empty() => "row_to_json(outbox_row)::text".to_string(),
+                PayloadMode::Row(columns) => format!(
+                    "(SELECT row_to_json(projected_row) FROM (SELECT {}) projected_row)::text",
+                    columns.iter().map(|x| format!("outbox_row.{}", x)).collect::<Vec<String>>().join(", ")
+                )
+            }
+        }
+
+        /// Returns the SQL statement deleting the rows whose ids are passed as a text array
+        fn delete_sql(&self, conn: &Connection) -> Result<String, Box<error::Error>> {
+            let rows = conn.query(
+                "SELECT format_type(atttypid, atttypmod) FROM pg_attribute
+                  WHERE attrelid = to_regclass($1::text) AND attname = 'id' AND NOT attisdropped",
+                &[&self.table_name]
+            )?;
+            if rows.is_empty() {
+                return Err(Box::from(format!("No such id column in table {}", self.table_name)));
+            }
+            let id_type: String = rows.get(0).get(0);
+            Ok(format!("DELETE FROM {} WHERE id = ANY($1::text[]::{}[])", self.table_name, id_type))
+        }
+
+        /// Returns the data in a JSON field, `None` if it is null.
+        /// Strings are returned as they are, other values as JSON.
+        fn json_data(value: &Value) -> Option<String> {
+            match value {
+                Value::Null => None,
+                Value::String(x) => Some(x.clone()),
+                x => Some(x.to_string())
+            }
+        }
+
+        /// Returns the message content of the [Plain](enum.OutboxFormat.html#variant.Plain) format,
+        /// restricted to the payload columns when there are some. Payloads having no other
+        /// column are returned as they are, e.g. those already restricted by the fallback query.
+        fn payload_data(&self, payload: &Value) -> Result<Option<String>, Box<error::Error>> {
+            let data = match Self::json_data(payload) {
+                Some(x) => x,
+                None => {
+                    return Ok(None);
+                }
+            };
+            let columns = match &self.payload_mode {
+                PayloadMode::Row(columns) if !columns.is_empty() => columns,
+                _ => {
+                    return Ok(Some(data));
+                }
+            };
+            let row: Map<String, Value> = match payload {
+                Value::Object(x) => x.clone(),
+                _ => serde_json::from_str(&data)?
+            };
+            if row.keys().all(|x| columns.contains(x)) {
+                return Ok(Some(data));
+            }
+            let projected: Map<String, Value> = columns.iter()
+                .map(|x| (x.clone(), row.get(x).cloned().unwrap_or(Value::Null)))
+                .collect();
+            Ok(Some(Value::Object(projected).to_string()))
+        }
+
+        /// Returns the id of the row, together with the [SourceElement](struct.SourceElement.html) to be sent
+        ///
+        /// Arguments:
+        /// * `row` - a row of the table as a JSON object, either from a notification or from a query
+        pub(crate) fn element_from_row(&self, row: &Value) -> Result<(String, common::SourceElement), Box<error::Error>> {
+            debug!(target: "postgres", "Received row {:?}", row);
+            let id = match Self::json_data(&row["id"]) {
+                Some(x) => x,
+                _ => {
+                    return Err(Box::from("No such ID"));
+                }
+            };
+            let mut element = match self.format {
+                OutboxFormat::Plain => {
+                    let key = match self.key_column_name {
+                        Some(_) => Self::json_data(&row["key"]),
+                        None => None
+                    };
+                    let mut headers = Vec::new();
+                    if let (Some(_), Some(x)) = (self.headers_column_name, row["headers"].as_object()) {
+                        for (name, value) in x {
+                            headers.push((Box::from(name.as_str()), Box::from(Self::json_data(value).unwrap_or_default())));
+                        }
+                    }
+                    common::SourceElement{
+                        id: Box::from(id.as_str()),
+                        key: key.map(Box::from),
+                        data: self.payload_data(&row["payload"])?.map(|x| Box::from(x.as_bytes())),
+                        headers,
+                        topic: None
+                    }
+                },
+                OutboxFormat::Debezium => {
+                    let aggregate_type = match row["aggregatetype"].as_str() {
+                        Some(x) => x,
+                        _ => {
+                            return Err(Box::from("No such aggregatetype"));
+                        }
+                    };
+                    let mut headers = vec![(Box::from("id"), Box::from(id.as_str()))];
+                    if let Some(x) = Self::json_data(&row["type"]) {
+                        headers.push((Box::from("type"), Box::from(x)));
+                    }
+                    common::SourceElement{
+                        id: Box::from(id.as_str()),
+                        key: Self::json_data(&row["aggregateid"]).map(Box::from),
+                        data: Self::json_data(&row["payload"]).map(|x| Box::from(x.as_bytes())),
+                        headers,
+                        topic: Some(Box::from(format!("{}{}", DEBEZIUM_TOPIC_PREFIX, aggregate_type)))
+                    }
+                }
+            };
+            if let Some(x) = &self.cloud_events {
+                element.headers.extend(x.headers(row, self.table_name)?);
+            }
+            Ok((id, element))
+        }
     }
     impl <'a> common::StreamProducer for PostgreSQLListenStreamProducer<'a> {
         fn produce(& self, consumer: &mut impl common::StreamConsumer) -> Result<(), Box<error::Error>> {
             let conn = Connection::connect(self.url, TlsMode::None)?;
+            let delete_sql = self.delete_sql(&conn)?;
             let notifications = conn.notifications();
             let mut it = notifications.timeout_iter(self.notify_timeout);
             conn.execute(&*format!("LISTEN \"{}\"", self.channel.to_string()), &[])?;
             loop {
                 info!(target: "postgres", "Fallback read data from buffer table");
-                let mut data_to_delete : Vec<i32> = Vec::new();
-                let sql = &format!("SELECT id, {} FROM {}", self.column_name, self.table_name);
-                for next_row in &conn.query(sql, &[])? {
-                    let id: i32 = next_row.get(0);
-                    let data: String = next_row.get(1);
-                    let bin_data : &[u8] = data.as_bytes();
-                    consumer.write(common::SourceElement{id: Box::from("123"), data: Box::from(bin_data)})?;
+                let mut data_to_delete : Vec<String> = Vec::new();
+                for next_row in &conn.query(&self.fallback_sql(), &[])? {
+                    let json_row: String = next_row.get(0);
+                    let (id, element) = self.element_from_row(&serde_json::from_str(&json_row)?)?;
+                    consumer.write(element)?;
                     data_to_delete.push(id);
                 }
                 info!(target: "postgres", "Fallback data pushed messages: {:?}", data_to_delete.len());
-                self.flush_consumer(&mut data_to_delete, consumer, &conn)?;
+                self.flush_consumer(&mut data_to_delete, consumer, &conn, &delete_sql)?;
                 let start_time = SystemTime::now();
                 loop {
                     if start_time.elapsed()? >= self.notify_timeout_total {
@@ -90,23 +304,12 @@ pub mod stream {
                             if let Some(x) = b {
                                 let json_payload : Value = serde_json::from_str(&x.payload)?;
                                 debug!(target: "postgres", "Received {:?} from PostGreSQL notification", json_payload);
-                                let string_payload: &str = match json_payload["payload"].as_str() {
-                                    Some(x) => x,
-                                    _ => {
-                                        return Err(Box::from("No such payload"));
-                                    }
-                                };
-                                let id : i32 = match json_payload["id"].as_i64() {
-                                    Some(x) => x as i32,
-                                    _ => {
-                                        return Err(Box::from("No such ID"));
-                                    }
-                                };
-                                consumer.write(common::SourceElement{id: Box::from("123"), data: Box::from(string_payload.as_bytes())})?;
+                                let (id, element) = self.element_from_row(&json_payload)?;
+                                consumer.write(element)?;
                                 data_to_delete.push(id);
                             } else {
                                 debug!(target: "postgres", "Notification timeout expired");
-                                self.flush_consumer(&mut data_to_delete, consumer, &conn)?;
+                                self.flush_consumer(&mut data_to_delete, consumer, &conn, &delete_sql)?;
                             }
                         },
                         Err(e) => {
@@ -116,8 +319,97 @@ pub mod stream {
                         }
                     }
                 }
-                self.flush_consumer(&mut data_to_delete, consumer, &conn)?;
+                self.flush_consumer(&mut data_to_delete, consumer, &conn, &delete_sql)?;
             }
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+    use serde_json::json;
+    use super::stream::{PostgreSQLListenStreamProducer, OutboxFormat, PayloadMode};
+
+    fn producer(format: OutboxFormat) -> PostgreSQLListenStreamProducer<'static> {
+        producer_with_payload_mode(format, PayloadMode::Column)
+    }
+
+    fn producer_with_payload_mode(format: OutboxFormat, payload_mode: PayloadMode) -> PostgreSQLListenStreamProducer<'static> {
+        PostgreSQLListenStreamProducer::new(
+            "a_postgresql_url", "events", "payload", payload_mode, Some("key"), Some("headers"), format, None,
+            "events.activity", Duration::from_secs(60), Duration::from_secs(3)
+        )
+    }
+
+    #[test]
+    fn plain_row() {
+        let (id, element) = producer(OutboxFormat::Plain).element_from_row(&json!({
+            "id": 12, "payload": "a_payload", "key": null, "headers": {"a_header": "a_value"}
+        })).unwrap();
+        assert_eq!("12", id);
+        assert_eq!(None, element.key);
+        assert_eq!(Some(Box::from(&b"a_payload"[..])), element.data);
+        assert_eq!(vec![(Box::from("a_header"), Box::from("a_value"))], element.headers);
+        assert_eq!(None, element.topic);
+    }
+
+    #[test]
+    fn tombstone_row() {
+        let (id, element) = producer(OutboxFormat::Plain).element_from_row(&json!({
+            "id": 13, "payload": null, "key": "customer-1", "headers": null
+        })).unwrap();
+        assert_eq!("13", id);
+        assert_eq!(Some(Box::from("customer-1")), element.key);
+        assert_eq!(None, element.data);
+        assert!(element.headers.is_empty());
+    }
+
+    #[test]
+    fn debezium_row() {
+        let (id, element) = producer(OutboxFormat::Debezium).element_from_row(&json!({
+            "id": "0b5f4d2e-4d4c-4d7e-9f55-8b1c2e0f8a11",
+            "aggregatetype": "order",
+            "aggregateid": "123",
+            "type": "OrderCreated",
+            "payload": {"amount": 10}
+        })).unwrap();
+        assert_eq!("0b5f4d2e-4d4c-4d7e-9f55-8b1c2e0f8a11", id);
+        assert_eq!(Some(Box::from("123")), element.key);
+        assert_eq!(Some(Box::from(&b"{\"amount\":10}"[..])), element.data);
+        assert_eq!(Some(Box::from("outbox.event.order")), element.topic);
+        assert_eq!(
+            vec![
+                (Box::from("id"), Box::from("0b5f4d2e-4d4c-4d7e-9f55-8b1c2e0f8a11")),
+                (Box::from("type"), Box::from("OrderCreated"))
+            ],
+            element.headers
+        );
+    }
+
+    #[test]
+    fn whole_row_payload() {
+        assert_eq!(
+            "SELECT row_to_json(new_row)::text FROM (SELECT id, row_to_json(outbox_row)::text AS payload, key AS key, headers AS headers FROM events outbox_row) new_row",
+            producer_with_payload_mode(OutboxFormat::Plain, PayloadMode::Row(Vec::new())).fallback_sql()
+        );
+        assert_eq!(
+            "SELECT row_to_json(new_row)::text FROM (SELECT id, (SELECT row_to_json(projected_row) FROM (SELECT outbox_row.amount, outbox_row.created_at) projected_row)::text AS payload, key AS key, headers AS headers FROM events outbox_row) new_row",
+            producer_with_payload_mode(OutboxFormat::Plain, PayloadMode::Row(vec!["amount".to_string(), "created_at".to_string()])).fallback_sql()
+        );
+    }
+
+    #[test]
+    fn notified_rows_are_projected() {
+        let producer = producer_with_payload_mode(OutboxFormat::Plain, PayloadMode::Row(vec!["amount".to_string(), "created_at".to_string()]));
+        let (_, element) = producer.element_from_row(&json!({
+            "id": 12, "payload": r#"{"id": 12, "amount": 10, "created_at": "2019-08-01T10:00:00", "secret": "a"}"#
+        })).unwrap();
+        assert_eq!(
+            json!({"amount": 10, "created_at": "2019-08-01T10:00:00"}),
+            serde_json::from_slice::<serde_json::Value>(&element.data.unwrap()).unwrap()
+        );
+        let (_, element) = producer.element_from_row(&json!({"id": 12, "payload": r#"{"amount":10.50}"#})).unwrap();
+        assert_eq!(Some(Box::from(&br#"{"amount":10.50}"#[..])), element.data);
+    }
+}
@@ -0,0 +1,158 @@
+pub mod stream {
+    extern crate prost;
+    extern crate prost_reflect;
+    use std::error;
+    use std::fs;
+    use std::boxed::Box;
+    use prost::Message;
+    use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+    use crate::common;
+    use crate::dead_letter::stream::DeadLetterQueue;
+    use log::{info, debug};
+
+    /// A stream consumer encoding JSON payloads as protobuf messages before passing them to
+    /// another consumer. Payloads not matching the message descriptor are sent to the dead letter
+    /// destination. Absent payloads, e.g. tombstones, are passed as they are.
+    pub struct ProtobufStreamConsumer<'a, C: common::StreamConsumer> {
+        /// The consumer receiving the encoded messages
+        consumer: C,
+        /// The descriptor of the protobuf message
+        descriptor: MessageDescriptor,
+        /// The destination of the payloads that can not be encoded
+        dead_letter: DeadLetterQueue<'a>
+    }
+
+    impl <'a, C: common::StreamConsumer> ProtobufStreamConsumer<'a, C> {
+        /// Returns a new Protobuf Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the encoded messages
+        /// * descriptor_set_filename - a file containing a serialized `FileDescriptorSet`, as
+        /// produced by `protoc --include_imports --descriptor_set_out`
+        /// * message_name - the fully qualified name of the protobuf message, e.g. `package.Message`
+        /// * dead_letter - the destination of the payloads that can not be encoded
+        pub fn new(consumer: C, descriptor_set_filename: &str, message_name: &str, dead_letter: DeadLetterQueue<'a>) -> Result<ProtobufStreamConsumer<'a, C>, Box<error::Error>> {
+            info!(target: "protobuf", "Using message {:?} from descriptor set {:?}", message_name, descriptor_set_filename);
+            let pool = DescriptorPool::decode(fs::read(descriptor_set_filename)?.as_slice())?;
+            let descriptor = match pool.get_message_by_name(message_name) {
+                Some(x) => x,
+                None => {
+                    return Err(Box::from(format!("No such message {} in {}", message_name, descriptor_set_filename)));
+                }
+            };
+            Ok(ProtobufStreamConsumer{consumer, descriptor, dead_letter})
+        }
+    }
+
+    /// Returns a JSON payload encoded as the specified protobuf message
+    pub fn encode_protobuf(descriptor: &MessageDescriptor, payload: &[u8]) -> Result<Vec<u8>, Box<error::Error>> {
+        let mut deserializer = serde_json::Deserializer::from_slice(payload);
+        let message = DynamicMessage::deserialize(descriptor.clone(), &mut deserializer)?;
+        deserializer.end()?;
+        Ok(message.encode_to_vec())
+    }
+
+    impl <'a, C: common::StreamConsumer> common::StreamConsumer for ProtobufStreamConsumer<'a, C> {
+        fn write(&mut self, mut element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let encoded = match element.data.as_ref().map(|data| encode_protobuf(&self.descriptor, data)) {
+                Some(x) => x,
+                None => {
+                    return self.consumer.write(element);
+                }
+            };
+            match encoded {
+                Ok(x) => {
+                    debug!(target: "protobuf", "Encoded element {:?}", element.id);
+                    element.data = Some(x.into_boxed_slice());
+                    self.consumer.write(element)
+                },
+                Err(e) => self.dead_letter.reject(element, e)
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.dead_letter.flush()?;
+            self.consumer.flush()
+        }
+
+        fn discard(&mut self) {
+            self.consumer.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    extern crate prost_types;
+    use std::env;
+    use std::fs;
+    use prost::Message;
+    use prost_types::{FileDescriptorSet, FileDescriptorProto, DescriptorProto, FieldDescriptorProto};
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use super::stream::ProtobufStreamConsumer;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{MemoryStreamConsumer, source_element};
+    use crate::dead_letter::stream::{DeadLetterQueue, DEAD_LETTER_ERROR_HEADER};
+
+    fn field(name: &str, number: i32, field_type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto{
+            name: Some(name.to_string()),
+            json_name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            ..Default::default()
+        }
+    }
+
+    fn descriptor_set_file() -> String {
+        let descriptor_set = FileDescriptorSet{
+            file: vec![FileDescriptorProto{
+                name: Some("event.proto".to_string()),
+                package: Some("test".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![DescriptorProto{
+                    name: Some("Event".to_string()),
+                    field: vec![field("name", 1, Type::String), field("count", 2, Type::Int32)],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }]
+        };
+        let filename = env::temp_dir().join(format!("push_the_elephant_test_event_{}.pb", rand::random::<u64>()));
+        fs::write(&filename, descriptor_set.encode_to_vec()).unwrap();
+        filename.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn invalid_payloads_are_sent_to_the_dead_letter_destination() {
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        let mut dead_letter_consumer = MemoryStreamConsumer::default();
+        {
+            let mut consumer = ProtobufStreamConsumer::new(
+                &mut memory_consumer,
+                &descriptor_set_file(),
+                "test.Event",
+                DeadLetterQueue::new(Some(Box::new(&mut dead_letter_consumer)))
+            ).unwrap();
+            consumer.write(source_element("1", r#"{"name": "a", "count": 1}"#)).unwrap();
+            consumer.write(source_element("2", r#"{"name": 5}"#)).unwrap();
+            consumer.flush().unwrap();
+        }
+        assert_eq!(1, memory_consumer.elements.len());
+        assert_eq!(Some(Box::from(&[0x0a, 1, b'a', 0x10, 1][..])), memory_consumer.elements[0].data);
+        assert_eq!(1, dead_letter_consumer.elements.len());
+        assert_eq!(1, dead_letter_consumer.flushes);
+        assert_eq!(Some(Box::from(&br#"{"name": 5}"#[..])), dead_letter_consumer.elements[0].data);
+        assert_eq!(&*dead_letter_consumer.elements[0].headers[0].0, DEAD_LETTER_ERROR_HEADER);
+    }
+
+    #[test]
+    fn invalid_payloads_fail_without_dead_letter_destination() {
+        let mut consumer = ProtobufStreamConsumer::new(
+            MemoryStreamConsumer::default(), &descriptor_set_file(), "test.Event", DeadLetterQueue::new(None)
+        ).unwrap();
+        assert!(consumer.write(source_element("1", r#"{"name": 5}"#)).is_err());
+    }
+}
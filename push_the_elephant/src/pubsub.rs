@@ -0,0 +1,235 @@
+pub mod stream {
+    extern crate base64;
+    extern crate ureq;
+    use std::env;
+    use std::error;
+    use std::time::{Duration, Instant};
+    use std::boxed::Box;
+    use serde_json::{json, Map, Value};
+    use crate::common;
+    use log::{info, debug};
+
+    /// The environment variable with the `host:port` of the Pub/Sub emulator
+    pub const PUBSUB_EMULATOR_HOST: &str = "PUBSUB_EMULATOR_HOST";
+
+    /// The Pub/Sub endpoint, used unless the emulator is configured
+    const PUBSUB_ENDPOINT: &str = "https://pubsub.googleapis.com";
+
+    /// The metadata server URL of the access tokens of the default service account
+    const METADATA_TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+    /// The largest number of messages of a publish request
+    const MAX_BATCH_SIZE: usize = 1000;
+
+    /// How long before their expiration the access tokens are renewed
+    const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(60);
+
+    /// A stream consumer publishing messages to Google Cloud Pub/Sub with the REST API. Every
+    /// flush publishes the buffered messages, and succeeds only once Pub/Sub has returned the
+    /// ids of every message.
+    ///
+    /// The Pub/Sub topic is the message topic when set, otherwise the configured one. The
+    /// ordering key is the message key, and the attributes are the headers.
+    /// When the [PUBSUB_EMULATOR_HOST](constant.PUBSUB_EMULATOR_HOST.html) environment variable
+    /// is set, messages are published to the emulator without authentication. Otherwise requests
+    /// are authenticated with the configured access token or, when there is none, with the
+    /// tokens of the default service account, obtained from the metadata server of Google Cloud.
+    /// Messages without payload, e.g. tombstones, are published with empty data when they have
+    /// headers, and skipped otherwise, since Pub/Sub messages need either data or attributes.
+    pub struct PubSubStreamConsumer<'a> {
+        agent: ureq::Agent,
+        endpoint: String,
+        emulator: bool,
+        project: &'a str,
+        topic: &'a str,
+        access_token: Option<&'a str>,
+        metadata_token: Option<(String, Instant)>,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> PubSubStreamConsumer<'a> {
+        /// Returns a new Pub/Sub Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * project - the Google Cloud project of the topics
+        /// * topic - the topic of the messages without topic
+        /// * access_token - the OAuth 2 access token of the requests, if any
+        pub fn new(project: &'a str, topic: &'a str, access_token: Option<&'a str>) -> PubSubStreamConsumer<'a> {
+            match env::var(PUBSUB_EMULATOR_HOST) {
+                Ok(x) => Self::with_endpoint(project, topic, access_token, format!("http://{}", x), true),
+                Err(_) => Self::with_endpoint(project, topic, access_token, PUBSUB_ENDPOINT.to_string(), false)
+            }
+        }
+
+        /// Returns a new Pub/Sub Stream Consumer publishing to the specified endpoint
+        ///
+        /// # Arguments
+        ///
+        /// * project - the Google Cloud project of the topics
+        /// * topic - the topic of the messages without topic
+        /// * access_token - the OAuth 2 access token of the requests, if any
+        /// * endpoint - the URL of the Pub/Sub service
+        /// * emulator - whether the endpoint is the emulator, whose requests are not authenticated
+        pub(crate) fn with_endpoint(project: &'a str, topic: &'a str, access_token: Option<&'a str>, endpoint: String, emulator: bool) -> PubSubStreamConsumer<'a> {
+            info!(target: "pubsub", "Creating Pub/Sub consumer for project {:?}, topic {:?} at {}", project, topic, endpoint);
+            PubSubStreamConsumer{
+                agent: ureq::Agent::new(), endpoint, emulator, project, topic, access_token,
+                metadata_token: None, buffer: Vec::new()
+            }
+        }
+
+        /// Returns the `Authorization` header of the requests, if any
+        fn authorization(&mut self) -> Result<Option<String>, Box<error::Error>> {
+            if self.emulator {
+                return Ok(None);
+            }
+            if let Some(x) = self.access_token {
+                return Ok(Some(format!("Bearer {}", x)));
+            }
+            let expired = self.metadata_token.as_ref().map_or(true, |x| Instant::now() + TOKEN_RENEWAL_MARGIN >= x.1);
+            if expired {
+                let response: Value = serde_json::from_str(
+                    &self.agent.get(METADATA_TOKEN_URL).set("Metadata-Flavor", "Google").call()?.into_string()?
+                )?;
+                let token = match response["access_token"].as_str() {
+                    Some(x) => x.to_string(),
+                    None => {
+                        return Err(Box::from("The metadata server returned no access token"));
+                    }
+                };
+                let expires_in = Duration::from_secs(response["expires_in"].as_u64().unwrap_or(0));
+                debug!(target: "pubsub", "Obtained an access token expiring in {:?}", expires_in);
+                self.metadata_token = Some((token, Instant::now() + expires_in));
+            }
+            Ok(self.metadata_token.as_ref().map(|x| format!("Bearer {}", x.0)))
+        }
+
+        fn publish(&mut self, topic: &str, messages: &[Value]) -> Result<(), Box<error::Error>> {
+            let url = format!("{}/v1/projects/{}/topics/{}:publish", self.endpoint, self.project, topic);
+            let mut request = self.agent.post(&url);
+            if let Some(x) = self.authorization()? {
+                request = request.set("Authorization", &x);
+            }
+            let response: Value = match request.send_json(json!({"messages": messages})) {
+                Ok(x) => serde_json::from_str(&x.into_string()?)?,
+                Err(ureq::Error::Status(status, response)) => {
+                    return Err(Box::from(format!(
+                        "Publishing to {} failed with status {}: {}", topic, status, response.into_string().unwrap_or_default()
+                    )));
+                },
+                Err(e) => {
+                    return Err(Box::new(e));
+                }
+            };
+            let ids = response["messageIds"].as_array().map_or(0, |x| x.len());
+            if ids != messages.len() {
+                return Err(Box::from(format!("Pub/Sub returned {} message ids for {} messages", ids, messages.len())));
+            }
+            debug!(target: "pubsub", "Published {} messages to {}", ids, topic);
+            Ok(())
+        }
+    }
+
+    /// Returns the Pub/Sub message of an element
+    pub(crate) fn pubsub_message(element: &common::SourceElement) -> Value {
+        let mut attributes = Map::new();
+        for (name, value) in element.headers.iter() {
+            attributes.insert(name.to_string(), Value::String(value.to_string()));
+        }
+        let mut message = json!({
+            "data": base64::encode(element.data.as_ref().map_or(&[][..], |x| &**x)),
+            "attributes": attributes
+        });
+        if let Some(x) = element.key.as_ref() {
+            message["orderingKey"] = json!(&**x);
+        }
+        message
+    }
+
+    impl <'a> common::StreamConsumer for PubSubStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            if element.data.as_ref().map_or(true, |x| x.is_empty()) && element.headers.is_empty() {
+                debug!(target: "pubsub", "Skipping element {:?} without payload nor headers", element.id);
+                return Ok(());
+            }
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            let mut batches: Vec<(String, Vec<Value>)> = Vec::new();
+            for element in self.buffer.iter() {
+                let topic = element.topic.as_ref().map_or(self.topic, |x| &**x);
+                match batches.iter_mut().find(|x| x.0 == topic) {
+                    Some(x) => x.1.push(pubsub_message(element)),
+                    None => batches.push((topic.to_string(), vec![pubsub_message(element)]))
+                }
+            }
+            for (topic, messages) in batches.iter() {
+                for chunk in messages.chunks(MAX_BATCH_SIZE) {
+                    self.publish(topic, chunk)?;
+                }
+            }
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::stream::*;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, http_stand_in};
+
+    #[test]
+    fn messages_are_published_to_the_emulator() {
+        let (url, requests) = http_stand_in(vec![(200, r#"{"messageIds": ["1", "2"]}"#), (200, r#"{"messageIds": ["3"]}"#)]);
+        let mut consumer = PubSubStreamConsumer::with_endpoint("a-project", "events", None, url, true);
+        let mut element = source_element("1", "a");
+        element.key = Some(Box::from("customer-1"));
+        element.headers.push((Box::from("type"), Box::from("created")));
+        consumer.write(element).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        let mut element = source_element("3", "c");
+        element.topic = Some(Box::from("audit"));
+        consumer.write(element).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!("POST /v1/projects/a-project/topics/events:publish HTTP/1.1", requests[0].0);
+        assert_eq!(
+            json!({"messages": [
+                {"data": "YQ==", "attributes": {"type": "created"}, "orderingKey": "customer-1"},
+                {"data": "Yg==", "attributes": {}}
+            ]}),
+            serde_json::from_str::<Value>(&requests[0].1).unwrap()
+        );
+        assert_eq!("POST /v1/projects/a-project/topics/audit:publish HTTP/1.1", requests[1].0);
+    }
+
+    #[test]
+    fn tombstones_are_published_only_with_headers() {
+        let (url, requests) = http_stand_in(vec![(200, r#"{"messageIds": ["1", "3"]}"#)]);
+        let mut consumer = PubSubStreamConsumer::with_endpoint("a-project", "events", None, url, true);
+        let mut element = source_element("1", "a");
+        element.data = None;
+        element.headers.push((Box::from("type"), Box::from("deleted")));
+        consumer.write(element).unwrap();
+        let mut element = source_element("2", "a");
+        element.data = None;
+        consumer.write(element).unwrap();
+        consumer.write(source_element("3", "b")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            json!({"messages": [{"data": "", "attributes": {"type": "deleted"}}, {"data": "Yg==", "attributes": {}}]}),
+            serde_json::from_str::<Value>(&requests[0].1).unwrap()
+        );
+    }
+}
@@ -0,0 +1,449 @@
+pub mod stream {
+    extern crate crossbeam_channel;
+    extern crate tungstenite;
+    extern crate base64;
+    use std::collections::{HashMap, VecDeque};
+    use std::error;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::boxed::Box;
+    use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+    use serde_json::{json, Map, Value};
+    use tungstenite::{Message, WebSocket};
+    use tungstenite::handshake::derive_accept_key;
+    use tungstenite::protocol::Role;
+    use crate::common;
+    use log::{info, debug, warn};
+
+    /// The time after which idle connections are sent a keep alive
+    const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+    /// How often idle WebSocket connections are checked for control frames, e.g. Close or Ping
+    const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// The largest number of events waiting to be sent to a subscriber, beyond which the
+    /// subscriber is disconnected
+    pub(crate) const MAX_PENDING_EVENTS: usize = 1000;
+
+    /// A message pushed to the subscribers
+    #[derive(Debug)]
+    pub(crate) struct PushEvent {
+        pub id: String,
+        pub topic: String,
+        pub key: Option<String>,
+        pub headers: Vec<(String, String)>,
+        pub payload: Option<String>,
+        /// Whether the payload, not being UTF-8, is encoded as base64
+        pub base64: bool
+    }
+
+    /// The replay buffer and the subscribers of a server
+    pub(crate) struct Hub {
+        replay: VecDeque<Arc<PushEvent>>,
+        replay_size: usize,
+        subscribers: Vec<(String, Sender<Arc<PushEvent>>)>
+    }
+
+    impl Hub {
+        /// Returns a new Hub keeping the specified number of events for the resuming clients
+        pub(crate) fn new(replay_size: usize) -> Hub {
+            Hub{replay: VecDeque::new(), replay_size, subscribers: Vec::new()}
+        }
+
+        /// Registers a subscriber of a topic, returning the receiver of its events and the events
+        /// following the specified one still in the replay buffer, or all the buffered events of
+        /// the topic when the specified one is no longer buffered
+        pub(crate) fn subscribe(&mut self, topic: &str, last_event_id: Option<&str>) -> (Receiver<Arc<PushEvent>>, Vec<Arc<PushEvent>>) {
+            let events: Vec<Arc<PushEvent>> = self.replay.iter().filter(|x| x.topic == topic).cloned().collect();
+            let missed = match last_event_id {
+                Some(id) => match events.iter().position(|x| x.id == id) {
+                    Some(x) => events[x + 1..].to_vec(),
+                    None => events
+                },
+                None => Vec::new()
+            };
+            let (sender, receiver) = crossbeam_channel::bounded(MAX_PENDING_EVENTS);
+            self.subscribers.push((topic.to_string(), sender));
+            (receiver, missed)
+        }
+
+        /// Sends an event to the subscribers of its topic, disconnecting those that went away or
+        /// fell too far behind. The latter can resume from the replay buffer once reconnected.
+        pub(crate) fn publish(&mut self, event: PushEvent) {
+            let event = Arc::new(event);
+            self.replay.push_back(Arc::clone(&event));
+            while self.replay.len() > self.replay_size {
+                self.replay.pop_front();
+            }
+            self.subscribers.retain(|(topic, sender)| {
+                if *topic != event.topic {
+                    return true;
+                }
+                match sender.try_send(Arc::clone(&event)) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!(target: "push_server", "Disconnecting a subscriber of {} more than {} events behind", topic, MAX_PENDING_EVENTS);
+                        false
+                    },
+                    Err(TrySendError::Disconnected(_)) => false
+                }
+            });
+        }
+    }
+
+    /// A stream consumer pushing messages live to browsers and dashboards, with an embedded HTTP
+    /// server exposing Server-Sent Events at `/sse/<topic>` and WebSockets at `/ws/<topic>`.
+    ///
+    /// The topic of a message is the message topic when set, otherwise the configured one. Event
+    /// ids are the message ids. The last messages are kept in a replay buffer, so that clients
+    /// resuming with the `Last-Event-ID` header, or with the `last_event_id` query parameter, get
+    /// the messages they missed. Clients not resuming get the new messages only.
+    /// Server-Sent Events carry the payloads as data, while WebSocket text messages are JSON objects
+    /// with the `id`, `topic`, `key`, `headers` and `payload` fields. Payloads that are not UTF-8
+    /// are sent as base64, flagged by the `base64` event type of Server-Sent Events and by a
+    /// `"payload_encoding": "base64"` field of WebSocket messages.
+    ///
+    /// Messages are delivered on flush, which does not wait for the clients. Clients more than
+    /// [MAX_PENDING_EVENTS](constant.MAX_PENDING_EVENTS.html) messages behind are disconnected, and
+    /// can resume from the replay buffer. WebSocket Ping frames are answered, and Close frames end
+    /// the connection.
+    pub struct PushServerStreamConsumer<'a> {
+        topic: &'a str,
+        address: SocketAddr,
+        hub: Arc<Mutex<Hub>>,
+        stopped: Arc<AtomicBool>,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> PushServerStreamConsumer<'a> {
+        /// Returns a new Push Server Stream Consumer, whose server is listening once returned
+        ///
+        /// # Arguments
+        ///
+        /// * address - the address the server listens to, e.g. `0.0.0.0:8080`
+        /// * topic - the topic of the messages without topic
+        /// * replay_size - the number of messages kept for the resuming clients
+        pub fn new(address: &str, topic: &'a str, replay_size: usize) -> Result<PushServerStreamConsumer<'a>, Box<error::Error>> {
+            let listener = TcpListener::bind(address)?;
+            let address = listener.local_addr()?;
+            info!(target: "push_server", "Listening to {} for SSE and WebSocket clients", address);
+            let hub = Arc::new(Mutex::new(Hub::new(replay_size)));
+            let stopped = Arc::new(AtomicBool::new(false));
+            let thread_hub = Arc::clone(&hub);
+            let thread_stopped = Arc::clone(&stopped);
+            thread::Builder::new().name(format!("Push server {}", address)).spawn(move || {
+                for stream in listener.incoming() {
+                    if thread_stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(x) => x,
+                        Err(e) => {
+                            warn!(target: "push_server", "Could not accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let hub = Arc::clone(&thread_hub);
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, hub) {
+                            debug!(target: "push_server", "Connection closed: {}", e);
+                        }
+                    });
+                }
+            })?;
+            Ok(PushServerStreamConsumer{topic, address, hub, stopped, buffer: Vec::new()})
+        }
+
+        /// Returns the address the server listens to
+        pub fn local_addr(&self) -> SocketAddr {
+            self.address
+        }
+    }
+
+    impl <'a> Drop for PushServerStreamConsumer<'a> {
+        fn drop(&mut self) {
+            self.stopped.store(true, Ordering::SeqCst);
+            // Disconnects the clients
+            self.hub.lock().unwrap().subscribers.clear();
+            // Wakes the server up, so that it stops listening
+            let _ = TcpStream::connect(self.address);
+        }
+    }
+
+    /// Returns an element as a pushed event, whose payload is encoded as base64 unless UTF-8
+    pub(crate) fn push_event(element: &common::SourceElement, topic: &str) -> PushEvent {
+        let (payload, base64) = match element.data.as_ref() {
+            Some(x) => match std::str::from_utf8(x) {
+                Ok(payload) => (Some(payload.to_string()), false),
+                Err(_) => (Some(base64::encode(x)), true)
+            },
+            None => (None, false)
+        };
+        PushEvent{
+            id: element.id.to_string(),
+            topic: element.topic.as_ref().map_or(topic, |x| &**x).to_string(),
+            key: element.key.as_ref().map(|x| x.to_string()),
+            headers: element.headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            payload,
+            base64
+        }
+    }
+
+    /// Returns the Server-Sent Event of an event
+    pub(crate) fn sse_event(event: &PushEvent) -> String {
+        let mut result = format!("id: {}\n", event.id);
+        if event.base64 {
+            result.push_str("event: base64\n");
+        }
+        for line in event.payload.as_ref().map_or("", |x| x.as_str()).split('\n') {
+            result.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        result.push('\n');
+        result
+    }
+
+    /// Returns the WebSocket message of an event
+    pub(crate) fn websocket_message(event: &PushEvent) -> String {
+        let mut headers = Map::new();
+        for (name, value) in event.headers.iter() {
+            headers.insert(name.clone(), Value::String(value.clone()));
+        }
+        let mut message = json!({"id": event.id, "topic": event.topic, "key": event.key, "headers": headers, "payload": event.payload});
+        if event.base64 {
+            message["payload_encoding"] = Value::String("base64".to_string());
+        }
+        message.to_string()
+    }
+
+    /// Serves a connection until the client goes away
+    fn serve(stream: TcpStream, hub: Arc<Mutex<Hub>>) -> Result<(), Box<error::Error>> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut headers = HashMap::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some(x) = header.find(':') {
+                headers.insert(header[..x].trim().to_lowercase(), header[x + 1..].trim().to_string());
+            }
+        }
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let (path, query) = match target.find('?') {
+            Some(x) => (&target[..x], &target[x + 1..]),
+            None => (target, "")
+        };
+        let last_event_id = headers.get("last-event-id").cloned().or_else(|| {
+            query.split('&').find(|x| x.starts_with("last_event_id=")).map(|x| x["last_event_id=".len()..].to_string())
+        });
+        let mut stream = stream;
+        if path.starts_with("/sse/") {
+            let (receiver, missed) = hub.lock().unwrap().subscribe(&path["/sse/".len()..], last_event_id.as_ref().map(|x| x.as_str()));
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                 Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n"
+            )?;
+            push(&receiver, missed, KEEP_ALIVE_INTERVAL, |x| {
+                match x {
+                    Some(event) => stream.write_all(sse_event(event).as_bytes())?,
+                    None => stream.write_all(b": keep alive\n\n")?
+                }
+                Ok(stream.flush()?)
+            })
+        } else if path.starts_with("/ws/") {
+            let key = match headers.get("sec-websocket-key") {
+                Some(x) => x.clone(),
+                None => {
+                    write!(stream, "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+                    return Ok(());
+                }
+            };
+            let (receiver, missed) = hub.lock().unwrap().subscribe(&path["/ws/".len()..], last_event_id.as_ref().map(|x| x.as_str()));
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            )?;
+            let mut websocket = WebSocket::from_raw_socket(stream, Role::Server, None);
+            let mut last_ping = Instant::now();
+            push(&receiver, missed, WEBSOCKET_POLL_INTERVAL, |x| {
+                match x {
+                    Some(event) => websocket.write_message(Message::Text(websocket_message(event)))?,
+                    None if last_ping.elapsed() >= KEEP_ALIVE_INTERVAL => {
+                        websocket.write_message(Message::Ping(Vec::new()))?;
+                        last_ping = Instant::now();
+                    },
+                    None => ()
+                }
+                read_control_frames(&mut websocket)
+            })
+        } else {
+            write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+            Ok(())
+        }
+    }
+
+    /// Reads the frames a WebSocket client sent, without waiting for more. Pings are answered
+    /// with Pongs, while Close frames are answered and end the connection.
+    fn read_control_frames(websocket: &mut WebSocket<TcpStream>) -> Result<(), Box<error::Error>> {
+        websocket.get_mut().set_nonblocking(true)?;
+        let result = loop {
+            match websocket.read_message() {
+                Ok(Message::Close(_)) => {
+                    // Sends the reply queued by the WebSocket
+                    let _ = websocket.write_pending();
+                    break Err(Box::from("The client closed the connection"));
+                },
+                Ok(_) => (),
+                Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break Ok(());
+                },
+                Err(e) => {
+                    break Err(Box::from(e));
+                }
+            }
+        };
+        websocket.get_mut().set_nonblocking(false)?;
+        result
+    }
+
+    /// Sends the missed events and then the new ones until sending fails, calling the function
+    /// without event whenever no event came for the specified interval
+    fn push<F>(receiver: &Receiver<Arc<PushEvent>>, missed: Vec<Arc<PushEvent>>, interval: Duration, mut send: F) -> Result<(), Box<error::Error>>
+        where F: FnMut(Option<&PushEvent>) -> Result<(), Box<error::Error>> {
+        for event in missed.iter() {
+            send(Some(event))?;
+        }
+        loop {
+            match receiver.recv_timeout(interval) {
+                Ok(event) => send(Some(&event))?,
+                Err(RecvTimeoutError::Timeout) => send(None)?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    impl <'a> common::StreamConsumer for PushServerStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            let mut hub = self.hub.lock().unwrap();
+            for element in self.buffer.drain(..) {
+                hub.publish(push_event(&element, self.topic));
+            }
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tungstenite::Message;
+    use super::stream::*;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn events_are_formatted() {
+        let mut element = source_element("1", "line 1\nline 2");
+        element.key = Some(Box::from("k"));
+        let event = push_event(&element, "events");
+        assert_eq!("id: 1\ndata: line 1\ndata: line 2\n\n", sse_event(&event));
+        assert_eq!(
+            json!({"id": "1", "topic": "events", "key": "k", "headers": {}, "payload": "line 1\nline 2"}),
+            serde_json::from_str::<Value>(&websocket_message(&event)).unwrap()
+        );
+    }
+
+    #[test]
+    fn binary_payloads_are_sent_as_base64() {
+        let mut element = source_element("1", "");
+        element.data = Some(Box::from(&[0xff, 0x00, 0xfe][..]));
+        let event = push_event(&element, "events");
+        assert_eq!("id: 1\nevent: base64\ndata: /wD+\n\n", sse_event(&event));
+        assert_eq!(
+            json!({"id": "1", "topic": "events", "key": null, "headers": {}, "payload": "/wD+", "payload_encoding": "base64"}),
+            serde_json::from_str::<Value>(&websocket_message(&event)).unwrap()
+        );
+    }
+
+    #[test]
+    fn sse_clients_resume_from_the_last_event_id() {
+        let mut consumer = PushServerStreamConsumer::new("127.0.0.1:0", "events", 10).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.flush().unwrap();
+        let mut stream = TcpStream::connect(consumer.local_addr()).unwrap();
+        write!(stream, "GET /sse/events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut response = String::new();
+        while !response.ends_with("\n\n") || !response.contains("id: ") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nid: 2\ndata: b\n\n"));
+    }
+
+    #[test]
+    fn websocket_clients_resume_from_the_last_event_id() {
+        let mut consumer = PushServerStreamConsumer::new("127.0.0.1:0", "events", 10).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.flush().unwrap();
+        let url = format!("ws://{}/ws/events?last_event_id=1", consumer.local_addr());
+        let (mut websocket, _) = tungstenite::client(&*url, TcpStream::connect(consumer.local_addr()).unwrap()).unwrap();
+        let message: Value = serde_json::from_str(&websocket.read_message().unwrap().into_text().unwrap()).unwrap();
+        assert_eq!(json!("2"), message["id"]);
+        assert_eq!(json!("b"), message["payload"]);
+    }
+
+    #[test]
+    fn slow_subscribers_are_disconnected() {
+        let mut hub = Hub::new(10);
+        let (receiver, _) = hub.subscribe("events", None);
+        for i in 0..MAX_PENDING_EVENTS + 1 {
+            hub.publish(push_event(&source_element(&i.to_string(), "a"), "events"));
+        }
+        assert_eq!(MAX_PENDING_EVENTS, receiver.iter().count());
+    }
+
+    #[test]
+    fn websocket_control_frames_are_answered() {
+        let consumer = PushServerStreamConsumer::new("127.0.0.1:0", "events", 10).unwrap();
+        let stream = TcpStream::connect(consumer.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let url = format!("ws://{}/ws/events", consumer.local_addr());
+        let (mut websocket, _) = tungstenite::client(&*url, stream).unwrap();
+        websocket.write_message(Message::Ping(b"ping".to_vec())).unwrap();
+        assert_eq!(Message::Pong(b"ping".to_vec()), websocket.read_message().unwrap());
+        websocket.close(None).unwrap();
+        loop {
+            match websocket.read_message() {
+                Ok(_) => (),
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                Err(e) => panic!("{}", e)
+            }
+        }
+    }
+}
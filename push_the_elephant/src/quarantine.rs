@@ -0,0 +1,140 @@
+pub mod stream {
+    use std::error;
+    use std::boxed::Box;
+    use postgres::{Connection, TlsMode};
+    use serde_json::{Map, Value};
+    use crate::common;
+    use crate::dead_letter::stream::DEAD_LETTER_ERROR_HEADER;
+    use log::{info, debug};
+
+    /// A stream consumer storing messages in a PostGreSQL quarantine table, typically used as
+    /// dead letter destination. The table is created unless it already exists, with the source
+    /// table, the message id, topic, key, payload, headers, the rejection error and the quarantine
+    /// time. Messages are keyed by source table and id, so that several outbox tables can share
+    /// the quarantine table, and are quarantined once, even when a failing flush makes them
+    /// rejected again.
+    pub struct PostgreSQLQuarantineStreamConsumer<'a> {
+        url: &'a str,
+        table_name: &'a str,
+        source_table: &'a str,
+        connection: Option<Connection>,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> PostgreSQLQuarantineStreamConsumer<'a> {
+        /// Returns a new PostGreSQL Quarantine Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * url - the PostGreSQL URL
+        /// * table_name - the quarantine table
+        /// * source_table - the outbox table the messages come from
+        pub fn new(url: &'a str, table_name: &'a str, source_table: &'a str) -> PostgreSQLQuarantineStreamConsumer<'a> {
+            info!(target: "quarantine", "Creating PostGreSQL quarantine for table {:?}, source table {:?}", table_name, source_table);
+            PostgreSQLQuarantineStreamConsumer{url, table_name, source_table, connection: None, buffer: Vec::new()}
+        }
+
+        pub(crate) fn create_sql(&self) -> String {
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                    source_table text NOT NULL, \
+                    id text NOT NULL, \
+                    topic text, \
+                    key text, \
+                    payload bytea, \
+                    headers jsonb, \
+                    error text, \
+                    quarantined_at timestamp with time zone NOT NULL DEFAULT now(), \
+                    PRIMARY KEY (source_table, id))",
+                self.table_name
+            )
+        }
+
+        pub(crate) fn insert_sql(&self) -> String {
+            format!(
+                "INSERT INTO {} (source_table, id, topic, key, payload, headers, error) VALUES ($1, $2, $3, $4, $5, $6::text::jsonb, $7) \
+                 ON CONFLICT (source_table, id) DO NOTHING",
+                self.table_name
+            )
+        }
+
+        fn connection(&mut self) -> Result<&Connection, Box<error::Error>> {
+            if self.connection.is_none() {
+                let conn = Connection::connect(self.url, TlsMode::None)?;
+                conn.batch_execute(&self.create_sql())?;
+                self.connection = Some(conn);
+            }
+            Ok(self.connection.as_ref().unwrap())
+        }
+    }
+
+    /// Returns the headers of a message as a JSON object, without the rejection error, and the
+    /// rejection error itself
+    pub(crate) fn split_error(element: &common::SourceElement) -> (String, Option<String>) {
+        let mut headers = Map::new();
+        let mut error = None;
+        for (name, value) in element.headers.iter() {
+            if &**name == DEAD_LETTER_ERROR_HEADER {
+                error = Some(value.to_string());
+            } else {
+                headers.insert(name.to_string(), Value::String(value.to_string()));
+            }
+        }
+        (Value::Object(headers).to_string(), error)
+    }
+
+    impl <'a> common::StreamConsumer for PostgreSQLQuarantineStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let insert_sql = self.insert_sql();
+            let source_table = self.source_table.to_string();
+            let elements: Vec<common::SourceElement> = self.buffer.drain(..).collect();
+            let conn = self.connection()?;
+            let transaction = conn.transaction()?;
+            {
+                let statement = transaction.prepare(&insert_sql)?;
+                for element in elements.iter() {
+                    debug!(target: "quarantine", "Quarantining element {:?}", element.id);
+                    let (headers, error) = split_error(element);
+                    let topic = element.topic.as_ref().map(|x| x.to_string());
+                    let key = element.key.as_ref().map(|x| x.to_string());
+                    let payload = element.data.as_ref().map(|x| x.to_vec());
+                    statement.execute(&[&source_table, &element.id.to_string(), &topic, &key, &payload, &headers, &error])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{PostgreSQLQuarantineStreamConsumer, split_error};
+    use crate::common::tests::source_element;
+    use crate::dead_letter::stream::DEAD_LETTER_ERROR_HEADER;
+
+    #[test]
+    fn quarantine_rows() {
+        let consumer = PostgreSQLQuarantineStreamConsumer::new("a_postgresql_url", "events_quarantine", "events");
+        assert_eq!(
+            "INSERT INTO events_quarantine (source_table, id, topic, key, payload, headers, error) VALUES ($1, $2, $3, $4, $5, $6::text::jsonb, $7) \
+             ON CONFLICT (source_table, id) DO NOTHING",
+            consumer.insert_sql()
+        );
+        let mut element = source_element("1", "{}");
+        element.headers.push((Box::from("type"), Box::from("created")));
+        element.headers.push((Box::from(DEAD_LETTER_ERROR_HEADER), Box::from("/count: not an integer")));
+        assert_eq!(
+            (r#"{"type":"created"}"#.to_string(), Some("/count: not an integer".to_string())),
+            split_error(&element)
+        );
+    }
+}
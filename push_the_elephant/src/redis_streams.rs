@@ -0,0 +1,145 @@
+pub mod stream {
+    extern crate redis;
+    use std::error;
+    use std::boxed::Box;
+    use serde_json::{Map, Value};
+    use crate::common;
+    use log::{info, debug};
+
+    /// The stream entry fields the message parts are written to
+    #[derive(Debug, Clone)]
+    pub struct RedisFieldMapping {
+        /// The field of the message id
+        pub id_field: String,
+        /// The field of the message key, omitted when the message has no key
+        pub key_field: String,
+        /// The field of the payload, omitted for tombstones
+        pub payload_field: String,
+        /// The field of the headers, as a JSON object, omitted when the message has no headers
+        pub headers_field: String
+    }
+
+    impl Default for RedisFieldMapping {
+        fn default() -> RedisFieldMapping {
+            RedisFieldMapping{
+                id_field: "id".to_string(),
+                key_field: "key".to_string(),
+                payload_field: "payload".to_string(),
+                headers_field: "headers".to_string()
+            }
+        }
+    }
+
+    /// A stream consumer adding messages to a Redis stream with `XADD`. Messages having a topic are
+    /// added to the stream of the same name.
+    pub struct RedisStreamConsumer<'a> {
+        connection: redis::Connection,
+        stream: &'a str,
+        max_len: Option<usize>,
+        fields: &'a RedisFieldMapping,
+        buffer: Vec<common::SourceElement>,
+        max_buffer_size: usize
+    }
+
+    impl <'a> RedisStreamConsumer<'a> {
+        /// Returns a new Redis Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * url - the Redis URL, e.g. `redis://localhost:6379`
+        /// * stream - the stream of the messages that do not specify their own
+        /// * max_len - the approximate maximum length of the streams, i.e. `XADD MAXLEN ~`, if any
+        /// * fields - the stream entry fields the message parts are written to
+        /// * max_buffer_size - the number of messages after which they are sent
+        pub fn new(url: &str, stream: &'a str, max_len: Option<usize>, fields: &'a RedisFieldMapping, max_buffer_size: usize) -> Result<RedisStreamConsumer<'a>, Box<error::Error>> {
+            info!(target: "redis", "Creating Redis consumer for {:?}, stream {:?}", url, stream);
+            let connection = redis::Client::open(url)?.get_connection()?;
+            Ok(RedisStreamConsumer{connection, stream, max_len, fields, buffer: Vec::new(), max_buffer_size})
+        }
+    }
+
+    /// Returns the `XADD` arguments of a message, after the stream name
+    pub(crate) fn xadd_arguments(element: &common::SourceElement, max_len: Option<usize>, fields: &RedisFieldMapping) -> Vec<Vec<u8>> {
+        let mut result: Vec<Vec<u8>> = Vec::new();
+        if let Some(x) = max_len {
+            result.push(b"MAXLEN".to_vec());
+            result.push(b"~".to_vec());
+            result.push(x.to_string().into_bytes());
+        }
+        result.push(b"*".to_vec());
+        result.push(fields.id_field.as_bytes().to_vec());
+        result.push(element.id.as_bytes().to_vec());
+        if let Some(x) = element.key.as_ref() {
+            result.push(fields.key_field.as_bytes().to_vec());
+            result.push(x.as_bytes().to_vec());
+        }
+        if let Some(x) = element.data.as_ref() {
+            result.push(fields.payload_field.as_bytes().to_vec());
+            result.push(x.to_vec());
+        }
+        if !element.headers.is_empty() {
+            let mut headers = Map::new();
+            for (name, value) in element.headers.iter() {
+                headers.insert(name.to_string(), Value::String(value.to_string()));
+            }
+            result.push(fields.headers_field.as_bytes().to_vec());
+            result.push(Value::Object(headers).to_string().into_bytes());
+        }
+        result
+    }
+
+    impl <'a> common::StreamConsumer for RedisStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            debug!(target: "redis", "Buffering element {:?}", element.id);
+            self.buffer.push(element);
+            if self.buffer.len() >= self.max_buffer_size {
+                return self.flush();
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let mut pipeline = redis::pipe();
+            pipeline.atomic();
+            for element in self.buffer.iter() {
+                let stream = element.topic.as_ref().map_or(self.stream, |x| &**x);
+                pipeline.cmd("XADD").arg(stream).arg(xadd_arguments(element, self.max_len, self.fields)).ignore();
+            }
+            pipeline.query::<()>(&mut self.connection)?;
+            debug!(target: "redis", "Flushed {} elements", self.buffer.len());
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{xadd_arguments, RedisFieldMapping};
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn xadd_arguments_follow_the_field_mapping() {
+        let mut element = source_element("1", "a");
+        element.key = Some(Box::from("k"));
+        element.headers.push((Box::from("type"), Box::from("created")));
+        let fields = RedisFieldMapping{payload_field: "value".to_string(), ..RedisFieldMapping::default()};
+        let arguments: Vec<String> = xadd_arguments(&element, Some(1000), &fields).into_iter().map(|x| String::from_utf8(x).unwrap()).collect();
+        assert_eq!(
+            vec!["MAXLEN", "~", "1000", "*", "id", "1", "key", "k", "value", "a", "headers", r#"{"type":"created"}"#],
+            arguments
+        );
+        element.data = None;
+        element.key = None;
+        element.headers.clear();
+        let arguments: Vec<String> = xadd_arguments(&element, None, &fields).into_iter().map(|x| String::from_utf8(x).unwrap()).collect();
+        assert_eq!(vec!["*", "id", "1"], arguments);
+    }
+}
@@ -0,0 +1,167 @@
+pub mod stream {
+    extern crate base64;
+    use std::error;
+    use std::boxed::Box;
+    use postgres::{Connection, TlsMode};
+    use serde_json::{json, Map, Value};
+    use crate::common;
+    use log::{info, debug};
+
+    /// A stream consumer copying messages into a PostGreSQL table, typically the inbox table of
+    /// another database. Every flush inserts the buffered messages with a single statement in its
+    /// own transaction, skipping the ids that are already in the table, so that messages sent
+    /// again after a failure are not duplicated.
+    ///
+    /// The table is created unless it already exists, with the message id as primary key, topic,
+    /// key, payload, headers and the insertion time. Existing tables must have at least the id,
+    /// topic, key, payload and headers columns, and a unique constraint on the id.
+    ///
+    /// A failed flush drops the connection, so that the next flush reconnects, e.g. after the
+    /// target database has been restarted.
+    pub struct PostgreSQLRelayStreamConsumer<'a> {
+        url: &'a str,
+        table_name: &'a str,
+        connection: Option<Connection>,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> PostgreSQLRelayStreamConsumer<'a> {
+        /// Returns a new PostGreSQL Relay Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * url - the PostGreSQL URL of the target database
+        /// * table_name - the target table, optionally qualified by its schema. Names are quoted,
+        /// hence case sensitive
+        pub fn new(url: &'a str, table_name: &'a str) -> PostgreSQLRelayStreamConsumer<'a> {
+            info!(target: "relay", "Creating PostGreSQL relay for table {:?}", table_name);
+            PostgreSQLRelayStreamConsumer{url, table_name, connection: None, buffer: Vec::new()}
+        }
+
+        pub(crate) fn create_sql(&self) -> String {
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                    id text PRIMARY KEY, \
+                    topic text, \
+                    key text, \
+                    payload bytea, \
+                    headers jsonb, \
+                    received_at timestamp with time zone NOT NULL DEFAULT now())",
+                quote_identifier(self.table_name)
+            )
+        }
+
+        pub(crate) fn insert_sql(&self) -> String {
+            format!(
+                "INSERT INTO {} (id, topic, key, payload, headers) \
+                 SELECT id, topic, key, decode(payload, 'base64'), headers \
+                 FROM json_to_recordset($1::text::json) AS x(id text, topic text, key text, payload text, headers jsonb) \
+                 ON CONFLICT (id) DO NOTHING",
+                quote_identifier(self.table_name)
+            )
+        }
+
+        fn connection(&mut self) -> Result<&Connection, Box<error::Error>> {
+            if self.connection.is_none() {
+                let conn = Connection::connect(self.url, TlsMode::None)?;
+                conn.batch_execute(&self.create_sql())?;
+                self.connection = Some(conn);
+            }
+            Ok(self.connection.as_ref().unwrap())
+        }
+
+        /// Inserts the rows in their own transaction, returning the number of inserted ones
+        fn insert(conn: &Connection, insert_sql: &str, rows: &str) -> Result<u64, Box<error::Error>> {
+            let transaction = conn.transaction()?;
+            let inserted = transaction.execute(insert_sql, &[&rows])?;
+            transaction.commit()?;
+            Ok(inserted)
+        }
+    }
+
+    /// Returns a table name, optionally qualified by its schema, as a quoted SQL identifier
+    pub(crate) fn quote_identifier(name: &str) -> String {
+        name.split('.').map(|x| format!("\"{}\"", x.replace('"', "\"\""))).collect::<Vec<String>>().join(".")
+    }
+
+    /// Returns the JSON array of the rows of some messages, whose payloads are base64 encoded
+    pub(crate) fn rows_json(elements: &[common::SourceElement]) -> String {
+        let rows: Vec<Value> = elements.iter().map(|element| {
+            let mut headers = Map::new();
+            for (name, value) in element.headers.iter() {
+                headers.insert(name.to_string(), Value::String(value.to_string()));
+            }
+            json!({
+                "id": &*element.id,
+                "topic": element.topic.as_ref().map(|x| &**x),
+                "key": element.key.as_ref().map(|x| &**x),
+                "payload": element.data.as_ref().map(|x| base64::encode(x)),
+                "headers": headers
+            })
+        }).collect();
+        Value::Array(rows).to_string()
+    }
+
+    impl <'a> common::StreamConsumer for PostgreSQLRelayStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let insert_sql = self.insert_sql();
+            let rows = rows_json(&self.buffer);
+            let inserted = match Self::insert(self.connection()?, &insert_sql, &rows) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.connection = None;
+                    return Err(e);
+                }
+            };
+            debug!(target: "relay", "Inserted {} elements, {} already present", inserted, self.buffer.len() as u64 - inserted);
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::stream::{PostgreSQLRelayStreamConsumer, rows_json, quote_identifier};
+    use crate::common::tests::source_element;
+
+    #[test]
+    fn relay_rows() {
+        let consumer = PostgreSQLRelayStreamConsumer::new("a_postgresql_url", "inbox");
+        assert_eq!(
+            "INSERT INTO \"inbox\" (id, topic, key, payload, headers) \
+             SELECT id, topic, key, decode(payload, 'base64'), headers \
+             FROM json_to_recordset($1::text::json) AS x(id text, topic text, key text, payload text, headers jsonb) \
+             ON CONFLICT (id) DO NOTHING",
+            consumer.insert_sql()
+        );
+        let mut element = source_element("1", "a");
+        element.key = Some(Box::from("k"));
+        element.headers.push((Box::from("type"), Box::from("created")));
+        let mut tombstone = source_element("2", "");
+        tombstone.data = None;
+        assert_eq!(
+            r#"[{"headers":{"type":"created"},"id":"1","key":"k","payload":"YQ==","topic":null},{"headers":{},"id":"2","key":null,"payload":null,"topic":null}]"#,
+            rows_json(&[element, tombstone])
+        );
+    }
+
+    #[test]
+    fn table_names_are_quoted() {
+        assert_eq!(r#""inbox""#, quote_identifier("inbox"));
+        assert_eq!(r#""relay"."Inbox""#, quote_identifier("relay.Inbox"));
+        assert_eq!(r#""in""box""#, quote_identifier(r#"in"box"#));
+    }
+}
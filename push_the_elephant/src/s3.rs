@@ -0,0 +1,350 @@
+pub mod stream {
+    extern crate flate2;
+    extern crate parquet;
+    extern crate ureq;
+    use std::error;
+    use std::fmt;
+    use std::io::Write;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::boxed::Box;
+    use chrono::{DateTime, Utc};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use parquet::data_type::{ByteArray, ByteArrayType, DataType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+    use parquet::schema::parser::parse_message_type;
+    use serde_json::{Map, Value};
+    use crate::common;
+    use crate::aws::signing::{AwsCredentials, signature_headers, uri_encode};
+    use crate::file::stream::{FilePayloadEncoding, json_line};
+    use log::{info, debug};
+
+    /// The format of the uploaded objects
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum S3ObjectFormat {
+        /// Gzip compressed JSON lines, those of the [FileStreamConsumer](struct.FileStreamConsumer.html)
+        JsonLines,
+        /// Snappy compressed Parquet, with the columns of the JSON lines
+        Parquet
+    }
+
+    #[derive(Debug)]
+    pub struct S3ObjectFormatError {
+        format: String
+    }
+
+    impl error::Error for S3ObjectFormatError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for S3ObjectFormatError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid object format {:?}", self.format)
+        }
+    }
+
+    impl FromStr for S3ObjectFormat {
+        type Err = S3ObjectFormatError;
+
+        fn from_str(s: &str) -> Result<S3ObjectFormat, S3ObjectFormatError> {
+            match s {
+                "jsonl" => Ok(S3ObjectFormat::JsonLines),
+                "parquet" => Ok(S3ObjectFormat::Parquet),
+                _ => Err(S3ObjectFormatError{format: s.to_string()})
+            }
+        }
+    }
+
+    impl S3ObjectFormat {
+        /// Returns the extension of the objects of this format
+        fn extension(&self) -> &'static str {
+            match self {
+                S3ObjectFormat::JsonLines => "jsonl.gz",
+                S3ObjectFormat::Parquet => "parquet"
+            }
+        }
+
+        /// Returns the content type of the objects of this format
+        fn content_type(&self) -> &'static str {
+            match self {
+                S3ObjectFormat::JsonLines => "application/gzip",
+                S3ObjectFormat::Parquet => "application/vnd.apache.parquet"
+            }
+        }
+    }
+
+    /// The schema of the Parquet objects, whose headers are a JSON object and whose timestamp is
+    /// the time the object has been written
+    const PARQUET_SCHEMA: &str = "
+        message event {
+            REQUIRED BYTE_ARRAY id (UTF8);
+            OPTIONAL BYTE_ARRAY key (UTF8);
+            OPTIONAL BYTE_ARRAY topic (UTF8);
+            REQUIRED BYTE_ARRAY headers (UTF8);
+            OPTIONAL BYTE_ARRAY payload;
+            REQUIRED INT64 timestamp (TIMESTAMP_MILLIS);
+        }
+    ";
+
+    /// A stream consumer uploading messages to an S3 compatible bucket, e.g. AWS S3 or MinIO, as
+    /// gzip compressed JSON lines or Parquet objects. Every flush uploads the buffered messages,
+    /// and succeeds only once the upload has succeeded.
+    ///
+    /// Lines are those of the [FileStreamConsumer](struct.FileStreamConsumer.html), and Parquet
+    /// objects have the same columns, payloads being binary. Objects are named
+    /// `<key prefix>/<source>-<id of the first message>-<id of the last message>.<extension>`,
+    /// where the key prefix is formatted with the `strftime` specifiers of the first flush time of
+    /// the buffered messages and `{topic}` is replaced by the message topic (`events` when not
+    /// set), one object being uploaded per key prefix. Retrying a failed flush hence overwrites the
+    /// objects already uploaded instead of duplicating them, while the objects of different
+    /// sources sharing a bucket do not overwrite one another.
+    pub struct S3StreamConsumer<'a> {
+        agent: ureq::Agent,
+        endpoint: &'a str,
+        bucket: &'a str,
+        region: &'a str,
+        credentials: &'a AwsCredentials,
+        key_prefix: &'a str,
+        format: S3ObjectFormat,
+        encoding: FilePayloadEncoding,
+        source: &'a str,
+        buffer: Vec<common::SourceElement>,
+        flushed_at: Option<DateTime<Utc>>
+    }
+
+    impl <'a> S3StreamConsumer<'a> {
+        /// Returns a new S3 Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * endpoint - the endpoint URL, e.g. `https://s3.eu-west-1.amazonaws.com` or
+        /// `http://localhost:9000`. Buckets are addressed in the path
+        /// * bucket - the bucket
+        /// * region - the region the requests are signed for
+        /// * credentials - the credentials the requests are signed with
+        /// * key_prefix - the template of the prefix of the object keys, e.g. `{topic}/dt=%Y-%m-%d/hour=%H`
+        /// * format - the format of the objects
+        /// * encoding - how payloads are written in the lines of JSON lines objects
+        /// * source - the name of the source of the messages, e.g. the outbox table, naming the objects
+        pub fn new(endpoint: &'a str, bucket: &'a str, region: &'a str, credentials: &'a AwsCredentials, key_prefix: &'a str, format: S3ObjectFormat, encoding: FilePayloadEncoding, source: &'a str) -> S3StreamConsumer<'a> {
+            info!(target: "s3", "Creating S3 consumer for bucket {:?} at {:?}", bucket, endpoint);
+            S3StreamConsumer{agent: ureq::Agent::new(), endpoint, bucket, region, credentials, key_prefix, format, encoding, source, buffer: Vec::new(), flushed_at: None}
+        }
+
+        fn put_object(&self, key: &str, body: &[u8]) -> Result<(), Box<error::Error>> {
+            let url = format!("{}/{}/{}", self.endpoint.trim_end_matches('/'), uri_encode(self.bucket, true), uri_encode(key, false));
+            let content_type = self.format.content_type();
+            let mut request = self.agent.put(&url).set("Content-Type", content_type);
+            for (name, value) in signature_headers(self.credentials, self.region, "s3", "PUT", &url, &[("Content-Type", content_type)], body)?.iter() {
+                request = request.set(name, value);
+            }
+            request.send_bytes(body)?;
+            debug!(target: "s3", "Uploaded {} bytes to {:?}", body.len(), key);
+            Ok(())
+        }
+    }
+
+    /// Returns the name of the object of some messages, made of the name of their source and the
+    /// ids of the first and the last one
+    pub(crate) fn object_name(source: &str, elements: &[&common::SourceElement], format: &S3ObjectFormat) -> String {
+        format!("{}-{}-{}.{}", source, elements[0].id, elements[elements.len() - 1].id, format.extension())
+    }
+
+    /// Returns the key prefix of a message
+    pub(crate) fn key_prefix(template: &str, element: &common::SourceElement, now: &DateTime<Utc>) -> String {
+        now.format(template).to_string()
+            .replace("{topic}", element.topic.as_ref().map_or("events", |x| &**x))
+            .trim_matches('/')
+            .to_string()
+    }
+
+    /// Returns the gzip compressed JSON lines of some messages
+    pub(crate) fn gzip_json_lines(elements: &[&common::SourceElement], encoding: &FilePayloadEncoding, now: &DateTime<Utc>) -> Result<Vec<u8>, Box<error::Error>> {
+        let timestamp = now.to_rfc3339();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for element in elements.iter() {
+            encoder.write_all(&json_line(element, encoding, &timestamp)?)?;
+            encoder.write_all(b"\n")?;
+        }
+        Ok(encoder.finish()?)
+    }
+
+    /// Writes the next column of a Parquet row group
+    fn write_column<T: DataType>(row_group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: &[T::T], definition_levels: Option<&[i16]>) -> Result<(), Box<error::Error>> {
+        let mut column = row_group.next_column()?.ok_or("Missing Parquet column")?;
+        column.typed::<T>().write_batch(values, definition_levels, None)?;
+        column.close()?;
+        Ok(())
+    }
+
+    /// Writes the next column of a Parquet row group, whose absent values are nulls
+    fn write_optional_column(row_group: &mut SerializedRowGroupWriter<'_, Vec<u8>>, values: Vec<Option<ByteArray>>) -> Result<(), Box<error::Error>> {
+        let definition_levels: Vec<i16> = values.iter().map(|x| if x.is_some() { 1 } else { 0 }).collect();
+        let values: Vec<ByteArray> = values.into_iter().flatten().collect();
+        write_column::<ByteArrayType>(row_group, &values, Some(&definition_levels))
+    }
+
+    /// Returns the snappy compressed Parquet object of some messages, made of one row group
+    pub(crate) fn parquet(elements: &[&common::SourceElement], now: &DateTime<Utc>) -> Result<Vec<u8>, Box<error::Error>> {
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+        let properties = Arc::new(WriterProperties::builder().set_compression(parquet::basic::Compression::SNAPPY).build());
+        let mut writer = SerializedFileWriter::new(Vec::new(), schema, properties)?;
+        let mut row_group = writer.next_row_group()?;
+        let ids: Vec<ByteArray> = elements.iter().map(|x| ByteArray::from(&*x.id)).collect();
+        write_column::<ByteArrayType>(&mut row_group, &ids, None)?;
+        write_optional_column(&mut row_group, elements.iter().map(|x| x.key.as_ref().map(|y| ByteArray::from(&**y))).collect())?;
+        write_optional_column(&mut row_group, elements.iter().map(|x| x.topic.as_ref().map(|y| ByteArray::from(&**y))).collect())?;
+        let mut headers = Vec::new();
+        for element in elements.iter() {
+            let mut object = Map::new();
+            for (name, value) in element.headers.iter() {
+                object.insert(name.to_string(), Value::String(value.to_string()));
+            }
+            headers.push(ByteArray::from(serde_json::to_vec(&object)?));
+        }
+        write_column::<ByteArrayType>(&mut row_group, &headers, None)?;
+        write_optional_column(&mut row_group, elements.iter().map(|x| x.data.as_ref().map(|y| ByteArray::from(y.to_vec()))).collect())?;
+        write_column::<Int64Type>(&mut row_group, &vec![now.timestamp_millis(); elements.len()], None)?;
+        row_group.close()?;
+        Ok(writer.into_inner()?)
+    }
+
+    impl <'a> common::StreamConsumer for S3StreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+            let now = *self.flushed_at.get_or_insert_with(Utc::now);
+            let mut batches: Vec<(String, Vec<&common::SourceElement>)> = Vec::new();
+            for element in self.buffer.iter() {
+                let prefix = key_prefix(self.key_prefix, element, &now);
+                match batches.iter_mut().find(|x| x.0 == prefix) {
+                    Some(x) => x.1.push(element),
+                    None => batches.push((prefix, vec![element]))
+                }
+            }
+            for (prefix, elements) in batches.iter() {
+                let name = object_name(self.source, elements, &self.format);
+                let key = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+                let body = match self.format {
+                    S3ObjectFormat::JsonLines => gzip_json_lines(elements, &self.encoding, &now)?,
+                    S3ObjectFormat::Parquet => parquet(elements, &now)?
+                };
+                self.put_object(&key, &body)?;
+            }
+            self.buffer.clear();
+            self.flushed_at = None;
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+            self.flushed_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Read;
+    use chrono::TimeZone;
+    use flate2::read::GzDecoder;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use super::stream::*;
+    use crate::aws::signing::AwsCredentials;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, http_stand_in};
+    use crate::file::stream::FilePayloadEncoding;
+
+    #[test]
+    fn key_prefixes_are_time_partitioned() {
+        let now = chrono::Utc.ymd(2019, 8, 1).and_hms(10, 30, 0);
+        let mut element = source_element("1", "a");
+        assert_eq!("events/dt=2019-08-01/hour=10", key_prefix("{topic}/dt=%Y-%m-%d/hour=%H", &element, &now));
+        element.topic = Some(Box::from("orders"));
+        assert_eq!("orders/dt=2019-08-01/hour=10", key_prefix("{topic}/dt=%Y-%m-%d/hour=%H/", &element, &now));
+    }
+
+    #[test]
+    fn messages_are_uploaded_as_gzip_json_lines() {
+        let now = chrono::Utc.ymd(2019, 8, 1).and_hms(10, 30, 0);
+        let elements = vec![source_element("1", "a"), source_element("2", "b")];
+        let mut lines = String::new();
+        GzDecoder::new(&gzip_json_lines(&elements.iter().collect::<Vec<_>>(), &FilePayloadEncoding::String, &now).unwrap()[..])
+            .read_to_string(&mut lines).unwrap();
+        assert_eq!(
+            "{\"headers\":{},\"id\":\"1\",\"key\":null,\"payload\":\"a\",\"timestamp\":\"2019-08-01T10:30:00+00:00\",\"topic\":null}\n\
+             {\"headers\":{},\"id\":\"2\",\"key\":null,\"payload\":\"b\",\"timestamp\":\"2019-08-01T10:30:00+00:00\",\"topic\":null}\n",
+            lines
+        );
+
+        let (url, requests) = http_stand_in(vec![(200, "")]);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = S3StreamConsumer::new(&url, "archive", "us-east-1", &credentials, "dt=%Y", S3ObjectFormat::JsonLines, FilePayloadEncoding::String, "outbox");
+        consumer.write(source_element("42", "a")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].0.starts_with("PUT /archive/dt%3D"));
+        assert!(requests[0].0.ends_with("/outbox-42-42.jsonl.gz HTTP/1.1"));
+    }
+
+    #[test]
+    fn retried_uploads_overwrite_the_same_objects() {
+        let (url, requests) = http_stand_in(vec![(500, ""), (200, "")]);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = S3StreamConsumer::new(&url, "archive", "us-east-1", &credentials, "%Y%m%dT%H%M%S%.f", S3ObjectFormat::JsonLines, FilePayloadEncoding::String, "outbox");
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        assert!(consumer.flush().is_err());
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!(requests[0].0, requests[1].0);
+        assert!(requests[1].0.ends_with("/outbox-1-2.jsonl.gz HTTP/1.1"));
+    }
+
+    #[test]
+    fn objects_of_different_sources_have_different_names() {
+        let elements = vec![source_element("1", "a"), source_element("2", "b")];
+        let elements: Vec<_> = elements.iter().collect();
+        assert_eq!("orders-1-2.jsonl.gz", object_name("orders", &elements, &S3ObjectFormat::JsonLines));
+        assert_eq!("invoices-1-2.parquet", object_name("invoices", &elements, &S3ObjectFormat::Parquet));
+    }
+
+    #[test]
+    fn messages_are_uploaded_as_parquet() {
+        let now = chrono::Utc.ymd(2019, 8, 1).and_hms(10, 30, 0);
+        let mut tombstone = source_element("2", "");
+        tombstone.data = None;
+        let elements = vec![source_element("1", "a"), tombstone];
+        let object = parquet(&elements.iter().collect::<Vec<_>>(), &now).unwrap();
+        assert_eq!(b"PAR1", &object[..4]);
+        let path = std::env::temp_dir().join("push_the_elephant_s3_messages_are_uploaded_as_parquet.parquet");
+        std::fs::write(&path, &object).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(2, metadata.num_rows());
+        assert_eq!(
+            vec!["id", "key", "topic", "headers", "payload", "timestamp"],
+            metadata.schema_descr().columns().iter().map(|x| x.name()).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let (url, requests) = http_stand_in(vec![(200, "")]);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = S3StreamConsumer::new(&url, "archive", "us-east-1", &credentials, "", S3ObjectFormat::Parquet, FilePayloadEncoding::String, "outbox");
+        consumer.write(source_element("42", "a")).unwrap();
+        consumer.flush().unwrap();
+        assert_eq!("PUT /archive/outbox-42-42.parquet HTTP/1.1", requests.lock().unwrap()[0].0);
+    }
+}
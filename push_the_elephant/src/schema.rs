@@ -0,0 +1,268 @@
+pub mod outbox {
+    extern crate postgres;
+    use std::error;
+    use std::boxed::Box;
+    use postgres::{Connection, TlsMode};
+    use postgres::transaction::Transaction;
+    use log::info;
+
+    /// The version of the outbox layout created by [OutboxSchema::init](struct.OutboxSchema.html#method.init)
+    ///
+    /// * version 1 - `id` and a non nullable payload column
+    /// * version 2 - nullable payload column, key and headers columns
+    pub const LATEST_SCHEMA_VERSION: i32 = 2;
+
+    /// The table where the applied schema versions are recorded
+    pub const SCHEMA_VERSION_TABLE: &str = "push_the_elephant_schema_version";
+
+    /// The layout of an outbox table, i.e. the table where messages to be sent are kept
+    #[derive(Debug)]
+    pub struct OutboxSchema<'a> {
+        /// The outbox table
+        pub table_name: &'a str,
+        /// The column where the message content resides
+        pub column_name: &'a str,
+        /// The column where the message key resides
+        pub key_column_name: &'a str,
+        /// The column where the message headers reside, as a JSON object
+        pub headers_column_name: &'a str,
+        /// The PostGreSQL channel where inserts are notified
+        pub channel: &'a str
+    }
+
+    impl <'a> OutboxSchema<'a> {
+        /// Creates the outbox table, its notification function and trigger, unless they already
+        /// exist. Returns the schema version of the outbox table.
+        ///
+        /// Arguments:
+        /// * `url` - PostGreSQL connection URL
+        pub fn init(&self, url: &str) -> Result<i32, Box<error::Error>> {
+            let conn = Connection::connect(url, TlsMode::None)?;
+            let transaction = conn.transaction()?;
+            self.create_version_table(&transaction)?;
+            self.lock_version_table(&transaction)?;
+            let version = match self.current_version(&transaction)? {
+                Some(version) => {
+                    info!(target: "schema", "Table {:?} already exists, schema version {:?}", self.table_name, version);
+                    version
+                },
+                None => {
+                    info!(target: "schema", "Creating table {:?}, schema version {:?}", self.table_name, LATEST_SCHEMA_VERSION);
+                    transaction.batch_execute(&format!(
+                        "CREATE TABLE {}(id SERIAL primary key, {} varchar, {} varchar, {} jsonb)",
+                        self.table_name, self.column_name, self.key_column_name, self.headers_column_name
+                    ))?;
+                    self.record_version(&transaction, LATEST_SCHEMA_VERSION)?;
+                    LATEST_SCHEMA_VERSION
+                }
+            };
+            self.install_notification_trigger(&transaction, version)?;
+            transaction.commit()?;
+            Ok(version)
+        }
+
+        /// Upgrades the outbox table to the [latest schema version](constant.LATEST_SCHEMA_VERSION.html).
+        /// Returns the schema version of the outbox table.
+        ///
+        /// Outbox tables that have not been created by [init](struct.OutboxSchema.html#method.init)
+        /// have their version detected from their columns.
+        ///
+        /// Arguments:
+        /// * `url` - PostGreSQL connection URL
+        pub fn migrate(&self, url: &str) -> Result<i32, Box<error::Error>> {
+            let conn = Connection::connect(url, TlsMode::None)?;
+            let transaction = conn.transaction()?;
+            self.create_version_table(&transaction)?;
+            self.lock_version_table(&transaction)?;
+            let mut version = match self.current_version(&transaction)? {
+                Some(version) => version,
+                None => {
+                    return Err(Box::from(format!("No such table {}", self.table_name)));
+                }
+            };
+            for sql in self.migration_steps(version)? {
+                info!(target: "schema", "Migrating table {:?} from schema version {:?}", self.table_name, version);
+                transaction.batch_execute(&sql)?;
+                version += 1;
+                self.record_version(&transaction, version)?;
+            }
+            self.install_notification_trigger(&transaction, version)?;
+            transaction.commit()?;
+            Ok(version)
+        }
+
+        /// Returns the SQL of every step upgrading the outbox table from the specified version to
+        /// the [latest one](constant.LATEST_SCHEMA_VERSION.html), in order
+        pub(crate) fn migration_steps(&self, version: i32) -> Result<Vec<String>, Box<error::Error>> {
+            if version > LATEST_SCHEMA_VERSION {
+                return Err(Box::from(format!(
+                    "Schema version {} of table {} is newer than the supported version {}",
+                    version, self.table_name, LATEST_SCHEMA_VERSION
+                )));
+            }
+            (version..LATEST_SCHEMA_VERSION).map(|x| self.migration_sql(x)).collect()
+        }
+
+        /// Returns the SQL that upgrades the outbox table from the specified version to the next one
+        fn migration_sql(&self, version: i32) -> Result<String, Box<error::Error>> {
+            match version {
+                1 => Ok(format!(
+                    "ALTER TABLE {table}
+                        ALTER COLUMN {column} DROP NOT NULL,
+                        ADD COLUMN IF NOT EXISTS {key} varchar,
+                        ADD COLUMN IF NOT EXISTS {headers} jsonb",
+                    table = self.table_name,
+                    column = self.column_name,
+                    key = self.key_column_name,
+                    headers = self.headers_column_name
+                )),
+                _ => Err(Box::from(format!("No such migration from schema version {}", version)))
+            }
+        }
+
+        /// Serializes the schema changes of concurrent `init` and `migrate` runs
+        fn lock_version_table(&self, transaction: &Transaction) -> Result<(), Box<error::Error>> {
+            transaction.batch_execute(&format!("LOCK TABLE {} IN EXCLUSIVE MODE", SCHEMA_VERSION_TABLE))?;
+            Ok(())
+        }
+
+        fn create_version_table(&self, transaction: &Transaction) -> Result<(), Box<error::Error>> {
+            transaction.batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {}(
+                    table_name varchar not null,
+                    version integer not null,
+                    applied_at timestamptz not null default now(),
+                    primary key(table_name, version)
+                )",
+                SCHEMA_VERSION_TABLE
+            ))?;
+            Ok(())
+        }
+
+        /// Returns the current schema version of the outbox table, `None` if the table does not exist.
+        /// The version of tables with no recorded version is detected and recorded.
+        fn current_version(&self, transaction: &Transaction) -> Result<Option<i32>, Box<error::Error>> {
+            let rows = transaction.query(
+                &format!("SELECT max(version) FROM {} WHERE table_name = $1", SCHEMA_VERSION_TABLE),
+                &[&self.table_name]
+            )?;
+            let version: Option<i32> = rows.get(0).get(0);
+            if version.is_some() {
+                return Ok(version);
+            }
+            let rows = transaction.query(
+                "SELECT to_regclass($1::text) IS NOT NULL, EXISTS(
+                    SELECT 1 FROM pg_attribute
+                     WHERE attrelid = to_regclass($1::text) AND attname = $2 AND NOT attisdropped
+                )",
+                &[&self.table_name, &self.headers_column_name]
+            )?;
+            let row = rows.get(0);
+            let table_exists: bool = row.get(0);
+            if !table_exists {
+                return Ok(None);
+            }
+            let version = detected_version(row.get(1));
+            info!(target: "schema", "Detected schema version {:?} for table {:?}", version, self.table_name);
+            self.record_version(transaction, version)?;
+            Ok(Some(version))
+        }
+
+        fn record_version(&self, transaction: &Transaction, version: i32) -> Result<(), Box<error::Error>> {
+            transaction.execute(
+                &format!("INSERT INTO {}(table_name, version) VALUES ($1, $2)", SCHEMA_VERSION_TABLE),
+                &[&self.table_name, &version]
+            )?;
+            Ok(())
+        }
+
+        /// Creates or replaces the function and the trigger notifying inserts in the outbox table.
+        ///
+        /// The notification is a JSON object with the `id` and `payload` fields, plus `key` and
+        /// `headers` from schema version 2 on.
+        fn install_notification_trigger(&self, transaction: &Transaction, version: i32) -> Result<(), Box<error::Error>> {
+            let suffix = self.table_name.replace(".", "_");
+            let new_row = if version >= 2 {
+                format!(
+                    "new_row(id, payload, key, headers) as (select NEW.id, NEW.{}, NEW.{}, NEW.{})",
+                    self.column_name, self.key_column_name, self.headers_column_name
+                )
+            } else {
+                format!("new_row(id, payload) as (select NEW.id, NEW.{})", self.column_name)
+            };
+            transaction.batch_execute(&format!(
+"CREATE OR REPLACE FUNCTION tg_notify_{suffix} ()
+ returns trigger
+ language plpgsql
+as $$
+declare
+  channel text := TG_ARGV[0];
+begin
+  PERFORM (
+     with {new_row}
+     select pg_notify(channel, row_to_json(new_row)::text)
+       from new_row
+  );
+  RETURN NULL;
+end;
+$$;
+
+DROP TRIGGER IF EXISTS notify_{suffix} ON {table};
+
+CREATE TRIGGER notify_{suffix}
+         AFTER INSERT
+            ON {table}
+      FOR EACH ROW
+       EXECUTE PROCEDURE tg_notify_{suffix}('{channel}');
+",
+                suffix = suffix,
+                new_row = new_row,
+                table = self.table_name,
+                channel = self.channel
+            ))?;
+            Ok(())
+        }
+    }
+
+    /// Returns the schema version of an existing outbox table without a recorded version
+    ///
+    /// Arguments:
+    /// * `has_headers` - whether the table has the headers column
+    pub(crate) fn detected_version(has_headers: bool) -> i32 {
+        if has_headers { 2 } else { 1 }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::outbox::*;
+
+    fn schema() -> OutboxSchema<'static> {
+        OutboxSchema{
+            table_name: "events", column_name: "payload", key_column_name: "key",
+            headers_column_name: "headers", channel: "events.activity"
+        }
+    }
+
+    #[test]
+    fn versions_are_detected_from_columns() {
+        assert_eq!(1, detected_version(false));
+        assert_eq!(2, detected_version(true));
+    }
+
+    #[test]
+    fn migration_steps_lead_to_the_latest_version() {
+        let steps = schema().migration_steps(1).unwrap();
+        assert_eq!(1, steps.len());
+        assert!(steps[0].contains("ALTER COLUMN payload DROP NOT NULL"));
+        assert!(steps[0].contains("ADD COLUMN IF NOT EXISTS key varchar"));
+        assert!(steps[0].contains("ADD COLUMN IF NOT EXISTS headers jsonb"));
+        assert!(schema().migration_steps(LATEST_SCHEMA_VERSION).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert!(schema().migration_steps(0).is_err());
+        assert!(schema().migration_steps(LATEST_SCHEMA_VERSION + 1).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+pub mod transform {
+    extern crate rhai;
+    use std::error;
+    use std::fmt;
+    use std::fs;
+    use std::boxed::Box;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+    use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+    use rhai::module_resolvers::DummyModuleResolver;
+    use serde_json::Value;
+    use crate::common;
+    use crate::transform::pipeline::Transform;
+    use log::{info, debug};
+
+    /// The limits of the scripts, so that a faulty script can not stall or exhaust the worker
+    #[derive(Debug, Clone)]
+    pub struct ScriptLimits {
+        /// The maximum number of operations of a script run. `0` means unlimited, leaving the run
+        /// bounded by `max_duration` only
+        pub max_operations: u64,
+        /// The maximum duration of a script run
+        pub max_duration: Duration,
+        /// The maximum length of the strings
+        pub max_string_size: usize,
+        /// The maximum length of the arrays
+        pub max_array_size: usize,
+        /// The maximum number of properties of the maps
+        pub max_map_size: usize
+    }
+
+    impl Default for ScriptLimits {
+        fn default() -> ScriptLimits {
+            ScriptLimits{
+                max_operations: 100_000,
+                max_duration: Duration::from_millis(100),
+                max_string_size: 1024 * 1024,
+                max_array_size: 10_000,
+                max_map_size: 10_000
+            }
+        }
+    }
+
+    thread_local! {
+        /// When the script run of the current thread started. Runs are timed per thread, since
+        /// transforms are shared by the worker threads
+        static RUN_STARTED: Cell<Instant> = Cell::new(Instant::now());
+    }
+
+    /// A [Transform](trait.Transform.html) running a [Rhai](https://rhai.rs) script on every message.
+    ///
+    /// The script gets the message as the `message` map, having the `id`, `key`, `payload`, `headers`
+    /// and `topic` properties, where JSON payloads are maps and arrays and other payloads are strings.
+    /// The script can change the message and:
+    /// * return nothing or `true` to send it
+    /// * return `false` to drop it
+    /// * return a message map, or an array of message maps, to send them instead
+    ///
+    /// A string payload is sent as it is, a `()` payload as a tombstone and any other payload as JSON.
+    ///
+    /// Scripts are sandboxed: they can not import modules nor `eval` code, and `print` and `debug`
+    /// go to the log instead of the standard output.
+    pub struct ScriptTransform {
+        engine: Engine,
+        ast: AST
+    }
+
+    impl fmt::Debug for ScriptTransform {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "ScriptTransform")
+        }
+    }
+
+    impl ScriptTransform {
+        /// Returns a new Script Transform
+        ///
+        /// # Arguments
+        ///
+        /// * script - the Rhai script
+        /// * limits - the limits of every script run
+        pub fn new(script: &str, limits: &ScriptLimits) -> Result<ScriptTransform, Box<error::Error>> {
+            let mut engine = Engine::new();
+            // The default resolver would let scripts import files from disk
+            engine.set_module_resolver(DummyModuleResolver::new());
+            engine.disable_symbol("eval");
+            engine.on_print(|x| info!(target: "scripting", "{}", x));
+            engine.on_debug(|x, _, position| debug!(target: "scripting", "{} at {}", x, position));
+            engine.set_max_operations(limits.max_operations);
+            engine.set_max_string_size(limits.max_string_size);
+            engine.set_max_array_size(limits.max_array_size);
+            engine.set_max_map_size(limits.max_map_size);
+            let max_duration = limits.max_duration;
+            engine.on_progress(move |_| {
+                if RUN_STARTED.with(|x| x.get().elapsed()) > max_duration {
+                    Some(Dynamic::from(format!("Script run longer than {:?}", max_duration)))
+                } else {
+                    None
+                }
+            });
+            let ast = engine.compile(script).map_err(|e| format!("Invalid script: {}", e))?;
+            Ok(ScriptTransform{engine, ast})
+        }
+
+        /// Returns a new Script Transform running the script in a file
+        pub fn from_file(filename: &str, limits: &ScriptLimits) -> Result<ScriptTransform, Box<error::Error>> {
+            info!(target: "scripting", "Using script {:?} with limits {:?}", filename, limits);
+            Self::new(&fs::read_to_string(filename)?, limits)
+        }
+    }
+
+    /// Returns a message map from an element
+    fn message(element: &common::SourceElement) -> Result<Map, Box<error::Error>> {
+        let mut result = Map::new();
+        result.insert("id".into(), Dynamic::from(element.id.to_string()));
+        result.insert("key".into(), element.key.as_ref().map_or(Dynamic::UNIT, |x| Dynamic::from(x.to_string())));
+        let payload = match element.data.as_ref() {
+            Some(x) => match serde_json::from_slice::<Value>(x) {
+                Ok(y) => rhai::serde::to_dynamic(y).map_err(|e| e.to_string())?,
+                Err(_) => Dynamic::from(String::from_utf8_lossy(x).to_string())
+            },
+            None => Dynamic::UNIT
+        };
+        result.insert("payload".into(), payload);
+        let mut headers = Map::new();
+        for (name, value) in element.headers.iter() {
+            headers.insert(name.to_string().into(), Dynamic::from(value.to_string()));
+        }
+        result.insert("headers".into(), Dynamic::from(headers));
+        result.insert("topic".into(), element.topic.as_ref().map_or(Dynamic::UNIT, |x| Dynamic::from(x.to_string())));
+        Ok(result)
+    }
+
+    /// Returns a string property of a message map, if any
+    fn string_property(message: &Map, name: &str) -> Option<Box<str>> {
+        match message.get(name) {
+            Some(x) if !x.is_unit() => Some(Box::from(x.to_string())),
+            _ => None
+        }
+    }
+
+    /// Returns an element from a message map
+    fn element(message: Dynamic) -> Result<common::SourceElement, Box<error::Error>> {
+        let message = match message.try_cast::<Map>() {
+            Some(x) => x,
+            None => {
+                return Err(Box::from("Script messages are expected to be maps"));
+            }
+        };
+        let id = match string_property(&message, "id") {
+            Some(x) => x,
+            None => {
+                return Err(Box::from("Script messages are expected to have an id"));
+            }
+        };
+        let data = match message.get("payload") {
+            Some(x) if x.is_unit() => None,
+            Some(x) if x.is_string() => Some(Box::from(x.to_string().as_bytes())),
+            Some(x) => {
+                let payload: Value = rhai::serde::from_dynamic(x).map_err(|e| e.to_string())?;
+                Some(serde_json::to_vec(&payload)?.into_boxed_slice())
+            },
+            None => None
+        };
+        let mut headers = Vec::new();
+        if let Some(x) = message.get("headers").and_then(|x| x.read_lock::<Map>()) {
+            for (name, value) in x.iter() {
+                headers.push((Box::from(name.as_str()), Box::from(value.to_string())));
+            }
+        }
+        Ok(common::SourceElement{
+            id,
+            key: string_property(&message, "key"),
+            data,
+            headers,
+            topic: string_property(&message, "topic")
+        })
+    }
+
+    impl Transform for ScriptTransform {
+        fn apply(&self, element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            let mut scope = Scope::new();
+            scope.push("message", message(&element)?);
+            RUN_STARTED.with(|x| x.set(Instant::now()));
+            let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+                .map_err(|e| format!("Script failed on element {}: {}", element.id, e))?;
+            debug!(target: "scripting", "Script returned {:?} for element {:?}", result, element.id);
+            if result.is_unit() || result.as_bool() == Ok(true) {
+                let message = scope.get_value::<Dynamic>("message").unwrap_or(Dynamic::UNIT);
+                return Ok(vec![self::element(message)?]);
+            }
+            if result.as_bool() == Ok(false) {
+                return Ok(vec![]);
+            }
+            if result.is_array() {
+                return result.cast::<Array>().into_iter().map(self::element).collect();
+            }
+            Ok(vec![self::element(result)?])
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use super::transform::{ScriptTransform, ScriptLimits};
+    use crate::common::tests::source_element;
+    use crate::transform::pipeline::Transform;
+
+    #[test]
+    fn scripts_change_route_and_drop_messages() {
+        let transform = ScriptTransform::new(r#"
+            if message.payload.kind == "test" {
+                return false;
+            }
+            if message.payload.amount > 100 {
+                message.topic = "big_orders";
+            }
+            message.payload.checked = true;
+            message.headers.origin = "script";
+        "#, &ScriptLimits::default()).unwrap();
+        let result = transform.apply(source_element("1", r#"{"kind": "order", "amount": 150}"#)).unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(Some(Box::from("big_orders")), result[0].topic);
+        assert_eq!(vec![(Box::from("origin"), Box::from("script"))], result[0].headers);
+        let payload: Value = serde_json::from_slice(result[0].data.as_ref().unwrap()).unwrap();
+        assert_eq!(json!({"kind": "order", "amount": 150, "checked": true}), payload);
+        assert_eq!(0, transform.apply(source_element("2", r#"{"kind": "test", "amount": 1}"#)).unwrap().len());
+    }
+
+    #[test]
+    fn scripts_are_limited() {
+        let limits = ScriptLimits{max_duration: Duration::from_secs(60), ..ScriptLimits::default()};
+        let transform = ScriptTransform::new("loop { }", &limits).unwrap();
+        assert!(transform.apply(source_element("1", "{}")).is_err());
+        let limits = ScriptLimits{max_operations: 0, max_duration: Duration::from_millis(10), ..ScriptLimits::default()};
+        let transform = ScriptTransform::new("loop { }", &limits).unwrap();
+        assert!(transform.apply(source_element("1", "{}")).is_err());
+    }
+
+    #[test]
+    fn scripts_are_sandboxed() {
+        let transform = ScriptTransform::new(r#"import "scripts/helpers" as helpers;"#, &ScriptLimits::default()).unwrap();
+        assert!(transform.apply(source_element("1", "{}")).is_err());
+        assert!(ScriptTransform::new(r#"eval("message.topic = \"audit\"");"#, &ScriptLimits::default()).is_err());
+        let transform = ScriptTransform::new(r#"print("logged"); debug(message.id);"#, &ScriptLimits::default()).unwrap();
+        assert_eq!(1, transform.apply(source_element("1", "{}")).unwrap().len());
+    }
+
+    #[test]
+    fn scripts_run_on_other_threads() {
+        let transform: Arc<Transform> = Arc::new(ScriptTransform::new("message.topic = \"audit\";", &ScriptLimits::default()).unwrap());
+        let shared = Arc::clone(&transform);
+        let result = thread::spawn(move || shared.apply(source_element("1", "{}")).unwrap()).join().unwrap();
+        assert_eq!(Some(Box::from("audit")), result[0].topic);
+    }
+}
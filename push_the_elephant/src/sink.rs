@@ -0,0 +1,232 @@
+pub mod configuration {
+    use std::error;
+    use std::time::Duration;
+    use std::boxed::Box;
+    use crate::common;
+    use crate::kafka;
+    use crate::tee::stream::{TeeStreamConsumer, TeeMode};
+    use crate::redis_streams::stream::{RedisStreamConsumer, RedisFieldMapping};
+    use crate::amqp::stream::AmqpStreamConsumer;
+    use crate::nats_jetstream::stream::NatsStreamConsumer;
+    use crate::webhook::stream::{WebhookStreamConsumer, WebhookBatching};
+    use crate::mqtt::stream::{MqttStreamConsumer, MqttVersion};
+    use crate::file::stream::{FileStreamConsumer, FilePayloadEncoding};
+    use crate::s3::stream::{S3StreamConsumer, S3ObjectFormat};
+    use crate::aws::signing::AwsCredentials;
+    use crate::relay::stream::PostgreSQLRelayStreamConsumer;
+    use crate::sqs::stream::SqsStreamConsumer;
+    use crate::kinesis::stream::KinesisStreamConsumer;
+    use crate::pubsub::stream::PubSubStreamConsumer;
+    use crate::push_server::stream::PushServerStreamConsumer;
+    use rumqttc::QoS;
+
+    /// The destination of the messages of a [Worker](struct.Worker.html)
+    #[derive(Debug, Clone)]
+    pub enum SinkConfiguration {
+        /// A Kafka topic
+        Kafka {
+            brokers: Vec<String>,
+            topic: String,
+            buffer_size: usize
+        },
+        /// A Redis stream
+        Redis {
+            url: String,
+            stream: String,
+            max_len: Option<usize>,
+            fields: RedisFieldMapping,
+            buffer_size: usize
+        },
+        /// An AMQP 0.9.1 exchange
+        Amqp {
+            url: String,
+            exchange: String,
+            routing_key: Option<String>
+        },
+        /// NATS JetStream subjects
+        Nats {
+            url: String,
+            subject: String,
+            buffer_size: usize
+        },
+        /// HTTP endpoints
+        Webhook {
+            url: String,
+            headers: Vec<(String, String)>,
+            secret: Option<String>,
+            batching: WebhookBatching,
+            timeout: Duration,
+            max_retries: u32,
+            buffer_size: usize
+        },
+        /// MQTT topics
+        Mqtt {
+            host: String,
+            port: u16,
+            client_id: String,
+            credentials: Option<(String, String)>,
+            version: MqttVersion,
+            topic: String,
+            qos: QoS,
+            ack_timeout: Duration,
+            buffer_size: usize
+        },
+        /// JSON lines files
+        File {
+            directory: String,
+            prefix: String,
+            encoding: FilePayloadEncoding,
+            max_file_size: Option<u64>,
+            max_file_age: Option<Duration>
+        },
+        /// An S3 compatible bucket
+        S3 {
+            endpoint: String,
+            bucket: String,
+            region: String,
+            credentials: AwsCredentials,
+            key_prefix: String,
+            format: S3ObjectFormat,
+            encoding: FilePayloadEncoding
+        },
+        /// A PostGreSQL table, e.g. the inbox of another database
+        PostgreSQL {
+            url: String,
+            table_name: String
+        },
+        /// An SQS queue
+        Sqs {
+            queue_url: String,
+            region: String,
+            credentials: AwsCredentials
+        },
+        /// A Kinesis data stream
+        Kinesis {
+            endpoint: String,
+            stream_name: String,
+            region: String,
+            credentials: AwsCredentials
+        },
+        /// Google Cloud Pub/Sub topics
+        PubSub {
+            project: String,
+            topic: String,
+            access_token: Option<String>
+        },
+        /// Server-Sent Events and WebSocket clients of an embedded HTTP server
+        PushServer {
+            address: String,
+            topic: String,
+            replay_size: usize
+        },
+        /// Several destinations, the first one being the primary one
+        Tee {
+            mode: TeeMode,
+            sinks: Vec<SinkConfiguration>
+        }
+    }
+
+    impl SinkConfiguration {
+        /// Returns the topic of the messages without topic, if this destination has one, e.g. to
+        /// name Avro subjects and to look up JSON schemas. The topic of a tee is the one of its
+        /// primary destination
+        pub fn topic(&self) -> Option<&str> {
+            match self {
+                SinkConfiguration::Kafka{topic, ..} => Some(topic),
+                SinkConfiguration::Redis{stream, ..} => Some(stream),
+                SinkConfiguration::Amqp{routing_key, ..} => routing_key.as_ref().map(|x| x.as_str()),
+                SinkConfiguration::PubSub{topic, ..} => Some(topic),
+                SinkConfiguration::PushServer{topic, ..} => Some(topic),
+                SinkConfiguration::Tee{sinks, ..} => sinks.first().and_then(|x| x.topic()),
+                _ => None
+            }
+        }
+
+        /// Returns the Kafka brokers of this destination, if it is a Kafka one, e.g. to send the
+        /// rejected messages to a dead letter topic. The brokers of a tee are the ones of its
+        /// primary destination
+        pub fn kafka_brokers(&self) -> Option<&Vec<String>> {
+            match self {
+                SinkConfiguration::Kafka{brokers, ..} => Some(brokers),
+                SinkConfiguration::Tee{sinks, ..} => sinks.first().and_then(|x| x.kafka_brokers()),
+                _ => None
+            }
+        }
+
+        /// Returns the consumer sending the messages to this destination
+        ///
+        /// # Arguments
+        ///
+        /// * source - the name of the source of the messages, i.e. the outbox table, telling apart
+        /// the messages of different sources sharing a destination
+        pub fn consumer<'a>(&'a self, source: &'a str) -> Result<Box<common::StreamConsumer + 'a>, Box<error::Error>> {
+            let consumer: Box<common::StreamConsumer + 'a> = match self {
+                SinkConfiguration::Kafka{brokers, topic, buffer_size} =>
+                    Box::new(kafka::stream::KafkaStreamConsumer::new(brokers.clone(), topic, *buffer_size)?),
+                SinkConfiguration::Redis{url, stream, max_len, fields, buffer_size} =>
+                    Box::new(RedisStreamConsumer::new(url, stream, *max_len, fields, *buffer_size)?),
+                SinkConfiguration::Amqp{url, exchange, routing_key} =>
+                    Box::new(AmqpStreamConsumer::new(url, exchange, routing_key.as_ref().map(|x| x.as_str()))?),
+                SinkConfiguration::Nats{url, subject, buffer_size} =>
+                    Box::new(NatsStreamConsumer::new(url, subject, source, *buffer_size)?),
+                SinkConfiguration::Webhook{url, headers, secret, batching, timeout, max_retries, buffer_size} =>
+                    Box::new(WebhookStreamConsumer::new(
+                        url, headers, secret.as_ref().map(|x| x.as_str()), batching.clone(), *timeout, *max_retries, *buffer_size
+                    )),
+                SinkConfiguration::Mqtt{host, port, client_id, credentials, version, topic, qos, ack_timeout, buffer_size} =>
+                    Box::new(MqttStreamConsumer::new(
+                        host, *port, client_id, credentials.as_ref().map(|x| (x.0.as_str(), x.1.as_str())),
+                        version.clone(), topic, *qos, *ack_timeout, *buffer_size
+                    )?),
+                SinkConfiguration::File{directory, prefix, encoding, max_file_size, max_file_age} =>
+                    Box::new(FileStreamConsumer::new(directory, prefix, encoding.clone(), *max_file_size, *max_file_age)?),
+                SinkConfiguration::S3{endpoint, bucket, region, credentials, key_prefix, format, encoding} =>
+                    Box::new(S3StreamConsumer::new(endpoint, bucket, region, credentials, key_prefix, format.clone(), encoding.clone(), source)),
+                SinkConfiguration::PostgreSQL{url, table_name} =>
+                    Box::new(PostgreSQLRelayStreamConsumer::new(url, table_name)),
+                SinkConfiguration::Sqs{queue_url, region, credentials} =>
+                    Box::new(SqsStreamConsumer::new(queue_url, region, credentials, source)?),
+                SinkConfiguration::Kinesis{endpoint, stream_name, region, credentials} =>
+                    Box::new(KinesisStreamConsumer::new(endpoint, stream_name, region, credentials)),
+                SinkConfiguration::PubSub{project, topic, access_token} =>
+                    Box::new(PubSubStreamConsumer::new(project, topic, access_token.as_ref().map(|x| x.as_str()))),
+                SinkConfiguration::PushServer{address, topic, replay_size} =>
+                    Box::new(PushServerStreamConsumer::new(address, topic, *replay_size)?),
+                SinkConfiguration::Tee{mode, sinks} => {
+                    let mut consumers = Vec::new();
+                    for sink in sinks.iter() {
+                        consumers.push(sink.consumer(source)?);
+                    }
+                    Box::new(TeeStreamConsumer::new(consumers, mode.clone())?)
+                }
+            };
+            Ok(consumer)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::configuration::SinkConfiguration;
+    use crate::tee::stream::TeeMode;
+    use crate::redis_streams::stream::RedisFieldMapping;
+
+    #[test]
+    fn topics_of_sinks() {
+        let kafka = SinkConfiguration::Kafka{brokers: vec![], topic: "orders".to_string(), buffer_size: 1};
+        assert_eq!(Some("orders"), kafka.topic());
+        assert_eq!(None, SinkConfiguration::Tee{mode: TeeMode::BestEffort, sinks: vec![]}.topic());
+        assert_eq!(Some("orders"), SinkConfiguration::Tee{mode: TeeMode::BestEffort, sinks: vec![kafka]}.topic());
+    }
+
+    #[test]
+    fn kafka_brokers_of_sinks() {
+        let kafka = SinkConfiguration::Kafka{brokers: vec!["localhost:9092".to_string()], topic: "orders".to_string(), buffer_size: 1};
+        let redis = SinkConfiguration::Redis{url: "redis://localhost".to_string(), stream: "events".to_string(), max_len: None, fields: RedisFieldMapping::default(), buffer_size: 1};
+        assert_eq!(Some(&vec!["localhost:9092".to_string()]), kafka.kafka_brokers());
+        assert_eq!(None, redis.kafka_brokers());
+        assert_eq!(None, SinkConfiguration::Tee{mode: TeeMode::BestEffort, sinks: vec![]}.kafka_brokers());
+        assert_eq!(None, SinkConfiguration::Tee{mode: TeeMode::BestEffort, sinks: vec![redis.clone(), kafka.clone()]}.kafka_brokers());
+        assert_eq!(Some(&vec!["localhost:9092".to_string()]), SinkConfiguration::Tee{mode: TeeMode::BestEffort, sinks: vec![kafka, redis]}.kafka_brokers());
+    }
+}
@@ -0,0 +1,289 @@
+pub mod stream {
+    extern crate base64;
+    extern crate ureq;
+    use std::collections::HashSet;
+    use std::error;
+    use std::thread;
+    use std::time::Duration;
+    use std::boxed::Box;
+    use serde_json::{json, Map, Value};
+    use crate::common;
+    use crate::aws::signing::{AwsCredentials, json_request};
+    use log::{info, debug, warn};
+
+    /// The largest number of messages of a SendMessageBatch request
+    const MAX_BATCH_SIZE: usize = 10;
+
+    /// How many times the failed entries of a batch are sent again
+    const MAX_RETRIES: u32 = 5;
+
+    /// The delay before the first retry, doubled at every further retry
+    const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+    /// A stream consumer sending messages to an SQS compatible queue, e.g. AWS SQS or LocalStack,
+    /// with SendMessageBatch requests of the JSON protocol. Every flush sends the buffered
+    /// messages, and succeeds only once every message has been accepted.
+    ///
+    /// Entries failing because of the service are sent again with an exponential backoff, while
+    /// entries rejected as invalid fail the flush. Headers are sent as string message attributes.
+    /// On FIFO queues, i.e. those whose URL ends with `.fifo`, the message group id is the message
+    /// key (the id when there is no key) and the deduplication id is the message id prefixed with
+    /// the name of the source of the messages, so that messages of different sources with the same
+    /// id are not discarded as duplicates of one another. A batch has
+    /// at most one message of every group, and the next message of a group is sent only once the
+    /// previous one has been accepted, so that retries keep the order of the group.
+    /// Payloads that are not valid SQS text are sent base64 encoded, with a `pte.encoding`
+    /// attribute set to `base64`. Absent payloads, e.g. tombstones, are not sent, since SQS
+    /// messages cannot be empty.
+    pub struct SqsStreamConsumer<'a> {
+        agent: ureq::Agent,
+        endpoint: String,
+        queue_url: &'a str,
+        region: &'a str,
+        credentials: &'a AwsCredentials,
+        source: &'a str,
+        buffer: Vec<common::SourceElement>
+    }
+
+    impl <'a> SqsStreamConsumer<'a> {
+        /// Returns a new SQS Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * queue_url - the queue URL, e.g. `https://sqs.eu-west-1.amazonaws.com/123456789012/orders.fifo`,
+        /// whose scheme and host are the endpoint
+        /// * region - the region the requests are signed for
+        /// * credentials - the credentials the requests are signed with
+        /// * source - the name of the source of the messages, e.g. the outbox table
+        pub fn new(queue_url: &'a str, region: &'a str, credentials: &'a AwsCredentials, source: &'a str) -> Result<SqsStreamConsumer<'a>, Box<error::Error>> {
+            info!(target: "sqs", "Creating SQS consumer for queue {:?}", queue_url);
+            let endpoint = match queue_url.find("://").and_then(|x| queue_url[x + 3..].find('/').map(|y| x + 3 + y)) {
+                Some(x) => queue_url[..x].to_string(),
+                None => {
+                    return Err(Box::from(format!("Invalid queue URL {}", queue_url)));
+                }
+            };
+            Ok(SqsStreamConsumer{agent: ureq::Agent::new(), endpoint, queue_url, region, credentials, source, buffer: Vec::new()})
+        }
+
+        /// Sends a batch, returning the ids of the entries failing because of the service
+        fn send_batch(&self, entries: &[Value]) -> Result<Vec<String>, Box<error::Error>> {
+            let request = json!({"QueueUrl": self.queue_url, "Entries": entries});
+            let response = json_request(
+                &self.agent, self.credentials, self.region, "sqs", &self.endpoint,
+                "AmazonSQS.SendMessageBatch", "application/x-amz-json-1.0", &request
+            )?;
+            let mut failed = Vec::new();
+            for entry in response["Failed"].as_array().into_iter().flat_map(|x| x.iter()) {
+                if entry["SenderFault"].as_bool().unwrap_or(false) {
+                    return Err(Box::from(format!("SQS rejected message {}: {} {}", entry["Id"], entry["Code"], entry["Message"])));
+                }
+                warn!(target: "sqs", "SQS failed to send message {}: {} {}", entry["Id"], entry["Code"], entry["Message"]);
+                failed.push(entry["Id"].as_str().unwrap_or_default().to_string());
+            }
+            Ok(failed)
+        }
+    }
+
+    /// The message attribute flagging base64 encoded bodies
+    pub const SQS_ENCODING_ATTRIBUTE: &str = "pte.encoding";
+
+    /// Returns whether a character is allowed in SQS message bodies
+    fn sqs_character(c: char) -> bool {
+        match c {
+            '\u{9}' | '\u{a}' | '\u{d}' => true,
+            '\u{20}'..='\u{d7ff}' | '\u{e000}'..='\u{fffd}' | '\u{10000}'..='\u{10ffff}' => true,
+            _ => false
+        }
+    }
+
+    /// Returns the group of a message on FIFO queues, i.e. its key or its id when there is no key
+    fn message_group(element: &common::SourceElement) -> &str {
+        element.key.as_ref().map_or(&*element.id, |x| &**x)
+    }
+
+    /// Returns the SendMessageBatch entry of a message
+    ///
+    /// # Arguments
+    ///
+    /// * entry_id - the id of the entry, unique in its batch
+    /// * element - the message
+    /// * source - the name of the source of the message, prefixing its deduplication id
+    /// * fifo - whether the queue is a FIFO queue
+    pub(crate) fn sqs_entry(entry_id: &str, element: &common::SourceElement, source: &str, fifo: bool) -> Value {
+        let mut attributes = Map::new();
+        for (name, value) in element.headers.iter() {
+            attributes.insert(name.to_string(), json!({"DataType": "String", "StringValue": &**value}));
+        }
+        let data: &[u8] = element.data.as_ref().map_or(&[][..], |x| &**x);
+        let body = match std::str::from_utf8(data) {
+            Ok(x) if x.chars().all(sqs_character) => x.to_string(),
+            _ => {
+                attributes.insert(SQS_ENCODING_ATTRIBUTE.to_string(), json!({"DataType": "String", "StringValue": "base64"}));
+                base64::encode(data)
+            }
+        };
+        let mut entry = json!({
+            "Id": entry_id,
+            "MessageBody": body,
+            "MessageAttributes": attributes
+        });
+        if fifo {
+            entry["MessageGroupId"] = json!(message_group(element));
+            entry["MessageDeduplicationId"] = json!(format!("{}:{}", source, element.id));
+        }
+        entry
+    }
+
+    impl <'a> common::StreamConsumer for SqsStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            if element.data.is_none() {
+                debug!(target: "sqs", "Skipping element {:?} without payload", element.id);
+                return Ok(());
+            }
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            let fifo = self.queue_url.ends_with(".fifo");
+            let mut pending: Vec<&common::SourceElement> = self.buffer.iter().collect();
+            let mut retries = 0;
+            while !pending.is_empty() {
+                // Only FIFO queues keep the order of a group, elsewhere every message is its own group
+                let batch = if fifo {
+                    common::first_of_every_key(&pending, message_group, MAX_BATCH_SIZE)
+                } else {
+                    common::first_of_every_key(&pending, |x| &*x.id, MAX_BATCH_SIZE)
+                };
+                let entries: Vec<Value> = batch.iter().enumerate()
+                    .map(|(i, x)| sqs_entry(&i.to_string(), pending[*x], self.source, fifo))
+                    .collect();
+                let failed = self.send_batch(&entries)?;
+                let sent: HashSet<usize> = batch.iter().enumerate()
+                    .filter(|(i, _)| !failed.contains(&i.to_string()))
+                    .map(|(_, x)| *x)
+                    .collect();
+                pending = pending.into_iter().enumerate().filter(|(i, _)| !sent.contains(i)).map(|(_, x)| x).collect();
+                if failed.is_empty() {
+                    retries = 0;
+                    continue;
+                }
+                if retries >= MAX_RETRIES {
+                    return Err(Box::from(format!("SQS failed to send {} messages", failed.len())));
+                }
+                thread::sleep(RETRY_BACKOFF * 2u32.pow(retries));
+                retries += 1;
+            }
+            debug!(target: "sqs", "Sent {} elements", self.buffer.len());
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::{json, Value};
+    use super::stream::*;
+    use crate::aws::signing::AwsCredentials;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, http_stand_in};
+
+    #[test]
+    fn fifo_entries_are_grouped_by_key() {
+        let mut element = source_element("42", "a");
+        element.key = Some(Box::from("customer-1"));
+        element.headers.push((Box::from("type"), Box::from("created")));
+        assert_eq!(
+            json!({
+                "Id": "0",
+                "MessageBody": "a",
+                "MessageAttributes": {"type": {"DataType": "String", "StringValue": "created"}},
+                "MessageGroupId": "customer-1",
+                "MessageDeduplicationId": "outbox:42"
+            }),
+            sqs_entry("0", &element, "outbox", true)
+        );
+        assert_eq!(None, sqs_entry("0", &element, "outbox", false).get("MessageGroupId"));
+    }
+
+    #[test]
+    fn failed_entries_are_sent_again() {
+        let (url, requests) = http_stand_in(vec![
+            (200, r#"{"Successful": [{"Id": "0"}], "Failed": [{"Id": "1", "SenderFault": false, "Code": "InternalError"}]}"#),
+            (200, r#"{"Successful": [{"Id": "1"}], "Failed": []}"#)
+        ]);
+        let queue_url = format!("{}/000000000000/orders", url);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = SqsStreamConsumer::new(&queue_url, "us-east-1", &credentials, "outbox").unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.write(source_element("2", "b")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert_eq!("POST / HTTP/1.1", requests[0].0);
+        let first: Value = serde_json::from_str(&requests[0].1).unwrap();
+        let second: Value = serde_json::from_str(&requests[1].1).unwrap();
+        assert_eq!(json!(queue_url), first["QueueUrl"]);
+        assert_eq!(2, first["Entries"].as_array().unwrap().len());
+        assert_eq!(1, second["Entries"].as_array().unwrap().len());
+        assert_eq!(json!("b"), second["Entries"][0]["MessageBody"]);
+    }
+
+    #[test]
+    fn rejected_entries_fail_the_flush() {
+        let (url, _) = http_stand_in(vec![
+            (200, r#"{"Successful": [], "Failed": [{"Id": "0", "SenderFault": true, "Code": "InvalidMessageContents"}]}"#)
+        ]);
+        let queue_url = format!("{}/000000000000/orders", url);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = SqsStreamConsumer::new(&queue_url, "us-east-1", &credentials, "outbox").unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        assert!(consumer.flush().is_err());
+    }
+
+    #[test]
+    fn binary_bodies_are_base64_encoded() {
+        let mut element = source_element("42", "");
+        element.data = Some(Box::from(&[0xffu8, 0x00, 0x61][..]));
+        assert_eq!(
+            json!({
+                "Id": "0",
+                "MessageBody": "/wBh",
+                "MessageAttributes": {"pte.encoding": {"DataType": "String", "StringValue": "base64"}}
+            }),
+            sqs_entry("0", &element, "outbox", false)
+        );
+        element.data = Some(Box::from(&b"a\x01"[..]));
+        assert_eq!(json!("YQE="), sqs_entry("0", &element, "outbox", false)["MessageBody"]);
+    }
+
+    #[test]
+    fn fifo_groups_are_sent_in_order() {
+        let (url, requests) = http_stand_in(vec![
+            (200, r#"{"Successful": [{"Id": "1"}], "Failed": [{"Id": "0", "SenderFault": false, "Code": "InternalError"}]}"#),
+            (200, r#"{"Successful": [{"Id": "0"}], "Failed": []}"#),
+            (200, r#"{"Successful": [{"Id": "0"}], "Failed": []}"#)
+        ]);
+        let queue_url = format!("{}/000000000000/orders.fifo", url);
+        let credentials = AwsCredentials{access_key_id: "a".to_string(), secret_access_key: "b".to_string(), session_token: None};
+        let mut consumer = SqsStreamConsumer::new(&queue_url, "us-east-1", &credentials, "outbox").unwrap();
+        for (id, data) in [("1", "a"), ("2", "b")].iter() {
+            let mut element = source_element(id, data);
+            element.key = Some(Box::from("customer-1"));
+            consumer.write(element).unwrap();
+        }
+        consumer.write(source_element("3", "c")).unwrap();
+        consumer.flush().unwrap();
+        let requests = requests.lock().unwrap();
+        let bodies: Vec<Vec<Value>> = requests.iter()
+            .map(|x| serde_json::from_str::<Value>(&x.1).unwrap()["Entries"].as_array().unwrap().iter().map(|y| y["MessageBody"].clone()).collect())
+            .collect();
+        assert_eq!(vec![vec![json!("a"), json!("c")], vec![json!("a")], vec![json!("b")]], bodies);
+    }
+}
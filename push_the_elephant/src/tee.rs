@@ -0,0 +1,193 @@
+pub mod stream {
+    use std::error;
+    use std::fmt;
+    use std::str::FromStr;
+    use std::boxed::Box;
+    use crate::common;
+    use log::warn;
+
+    /// How the failures of the secondary consumers of a [TeeStreamConsumer](struct.TeeStreamConsumer.html) are handled
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum TeeMode {
+        /// Every consumer must succeed, so that messages are acknowledged only once all of them have them
+        AllMustSucceed,
+        /// Failures of the secondary consumers are logged and ignored
+        BestEffort
+    }
+
+    #[derive(Debug)]
+    pub struct TeeModeError {
+        mode: String
+    }
+
+    impl error::Error for TeeModeError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for TeeModeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid tee mode {:?}", self.mode)
+        }
+    }
+
+    impl FromStr for TeeMode {
+        type Err = TeeModeError;
+
+        fn from_str(s: &str) -> Result<TeeMode, TeeModeError> {
+            match s {
+                "all" => Ok(TeeMode::AllMustSucceed),
+                "best_effort" => Ok(TeeMode::BestEffort),
+                _ => Err(TeeModeError{mode: s.to_string()})
+            }
+        }
+    }
+
+    /// A stream consumer sending every message to several consumers. The first consumer is the
+    /// primary one, whose failures are always reported; the failures of the other ones are handled
+    /// according to the [TeeMode](enum.TeeMode.html).
+    pub struct TeeStreamConsumer<'a> {
+        consumers: Vec<Box<common::StreamConsumer + 'a>>,
+        mode: TeeMode
+    }
+
+    impl <'a> TeeStreamConsumer<'a> {
+        /// Returns a new Tee Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumers - the consumers, the first one being the primary one. There must be at least
+        /// one, otherwise messages would be acknowledged without being sent anywhere
+        /// * mode - how the failures of the secondary consumers are handled
+        pub fn new(consumers: Vec<Box<common::StreamConsumer + 'a>>, mode: TeeMode) -> Result<TeeStreamConsumer<'a>, Box<error::Error>> {
+            if consumers.is_empty() {
+                return Err(Box::from("A tee needs at least one consumer"));
+            }
+            Ok(TeeStreamConsumer{consumers, mode})
+        }
+
+        /// Returns the result of a consumer, ignoring the failures of the secondary ones in best
+        /// effort mode. Their pending messages are discarded, so that a secondary consumer being
+        /// down neither piles them up nor sends them again at every flush.
+        fn handle(&mut self, index: usize, result: Result<(), Box<error::Error>>) -> Result<(), Box<error::Error>> {
+            match result {
+                Err(e) if index > 0 && self.mode == TeeMode::BestEffort => {
+                    warn!(target: "tee", "Ignoring failure of secondary consumer {}, discarding its pending messages: {}", index, e);
+                    self.consumers[index].discard();
+                    Ok(())
+                },
+                x => x
+            }
+        }
+    }
+
+    impl <'a> common::StreamConsumer for TeeStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let last = self.consumers.len().saturating_sub(1);
+            let mut element = Some(element);
+            for index in 0..self.consumers.len() {
+                let e = if index == last { element.take().unwrap() } else { element.as_ref().unwrap().clone() };
+                let result = self.consumers[index].write(e);
+                self.handle(index, result)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            for index in 0..self.consumers.len() {
+                let result = self.consumers[index].flush();
+                self.handle(index, result)?;
+            }
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            for consumer in self.consumers.iter_mut() {
+                consumer.discard();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error;
+    use super::stream::{TeeStreamConsumer, TeeMode};
+    use crate::common::{SourceElement, StreamConsumer};
+    use crate::common::tests::{MemoryStreamConsumer, source_element};
+
+    /// A [StreamConsumer](../../trait.StreamConsumer.html) buffering the written elements and
+    /// always failing to flush them
+    #[derive(Default)]
+    struct FailingStreamConsumer {
+        buffer: Vec<SourceElement>,
+        flushed: Vec<usize>
+    }
+
+    impl StreamConsumer for FailingStreamConsumer {
+        fn write(&mut self, element: SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.flushed.push(self.buffer.len());
+            Err(Box::from("unavailable"))
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+
+    #[test]
+    fn messages_are_sent_to_every_consumer() {
+        let mut primary = MemoryStreamConsumer::default();
+        let mut secondary = MemoryStreamConsumer::default();
+        {
+            let mut consumer = TeeStreamConsumer::new(vec![Box::new(&mut primary), Box::new(&mut secondary)], TeeMode::AllMustSucceed).unwrap();
+            consumer.write(source_element("1", "a")).unwrap();
+            consumer.flush().unwrap();
+        }
+        assert_eq!(1, primary.elements.len());
+        assert_eq!(1, primary.flushes);
+        assert_eq!(1, secondary.elements.len());
+        assert_eq!(1, secondary.flushes);
+    }
+
+    #[test]
+    fn empty_tees_are_rejected() {
+        assert!(TeeStreamConsumer::new(Vec::new(), TeeMode::AllMustSucceed).is_err());
+    }
+
+    #[test]
+    fn secondary_failures_depend_on_the_mode() {
+        let mut consumer = TeeStreamConsumer::new(vec![Box::new(MemoryStreamConsumer::default()), Box::new(FailingStreamConsumer::default())], TeeMode::AllMustSucceed).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        assert!(consumer.flush().is_err());
+        let mut consumer = TeeStreamConsumer::new(vec![Box::new(MemoryStreamConsumer::default()), Box::new(FailingStreamConsumer::default())], TeeMode::BestEffort).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        consumer.flush().unwrap();
+        let mut consumer = TeeStreamConsumer::new(vec![Box::new(FailingStreamConsumer::default()), Box::new(MemoryStreamConsumer::default())], TeeMode::BestEffort).unwrap();
+        consumer.write(source_element("1", "a")).unwrap();
+        assert!(consumer.flush().is_err());
+    }
+
+    #[test]
+    fn best_effort_discards_the_messages_of_failed_secondaries() {
+        let mut primary = MemoryStreamConsumer::default();
+        let mut secondary = FailingStreamConsumer::default();
+        {
+            let mut consumer = TeeStreamConsumer::new(vec![Box::new(&mut primary), Box::new(&mut secondary)], TeeMode::BestEffort).unwrap();
+            for id in 0..3 {
+                consumer.write(source_element(&id.to_string(), "a")).unwrap();
+                consumer.write(source_element(&id.to_string(), "b")).unwrap();
+                consumer.flush().unwrap();
+            }
+        }
+        assert_eq!(6, primary.elements.len());
+        assert_eq!(vec![2, 2, 2], secondary.flushed);
+        assert!(secondary.buffer.is_empty());
+    }
+}
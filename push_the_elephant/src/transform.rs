@@ -0,0 +1,321 @@
+pub mod pipeline {
+    use std::error;
+    use std::fmt;
+    use std::boxed::Box;
+    use std::sync::Arc;
+    use serde_json::{Map, Value};
+    use crate::common;
+    use log::debug;
+
+    /// The separator between an id and the suffix of the ids derived from it
+    pub const DERIVED_ID_SEPARATOR: char = '#';
+
+    /// Returns the id of a message derived from another one by a transform, e.g. a split item or
+    /// a masked copy, so that sinks deduplicating by id keep every derived message. The id is the
+    /// original id followed by the [DERIVED_ID_SEPARATOR](constant.DERIVED_ID_SEPARATOR.html), the
+    /// transform name, `-` and the part, e.g. `42#split-0`.
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the original message
+    /// * transform - the name of the transform deriving the message
+    /// * part - what tells the derived messages of the same original one apart, e.g. an index
+    pub fn derived_id(id: &str, transform: &str, part: &str) -> Box<str> {
+        Box::from(format!("{}{}{}-{}", id, DERIVED_ID_SEPARATOR, transform, part))
+    }
+
+    /// Transforms a [SourceElement](struct.SourceElement.html) into any number of elements,
+    /// e.g. none to drop it, one to change it or many to split it. Transforms are shared by the
+    /// worker threads, hence they are `Send` and `Sync`
+    pub trait Transform: fmt::Debug + Send + Sync {
+        /// Returns the elements replacing the specified one
+        ///
+        /// # Arguments:
+        ///
+        /// * `element` - the element to be transformed
+        fn apply(&self, element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>>;
+    }
+
+    /// A stream consumer applying a chain of transforms to the elements before passing them
+    /// to another consumer. Every transform receives the elements returned by the previous one.
+    pub struct TransformStreamConsumer<C: common::StreamConsumer> {
+        /// The consumer receiving the transformed messages
+        consumer: C,
+        /// The transforms, in order
+        transforms: Vec<Arc<Transform>>
+    }
+
+    impl <C: common::StreamConsumer> TransformStreamConsumer<C> {
+        /// Returns a new Transform Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the transformed messages
+        /// * transforms - the transforms, applied in order
+        pub fn new(consumer: C, transforms: Vec<Arc<Transform>>) -> TransformStreamConsumer<C> {
+            TransformStreamConsumer{consumer, transforms}
+        }
+    }
+
+    impl <C: common::StreamConsumer> common::StreamConsumer for TransformStreamConsumer<C> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let mut elements = vec![element];
+            for transform in self.transforms.iter() {
+                let mut transformed = Vec::new();
+                for e in elements.into_iter() {
+                    transformed.extend(transform.apply(e)?);
+                }
+                elements = transformed;
+            }
+            for e in elements.into_iter() {
+                debug!(target: "transform", "Writing transformed element {:?}", e.id);
+                self.consumer.write(e)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.consumer.flush()
+        }
+
+        fn discard(&mut self) {
+            self.consumer.discard();
+        }
+    }
+
+    /// Returns the JSON payload of an element, if any
+    fn json_payload(element: &common::SourceElement) -> Result<Option<Value>, Box<error::Error>> {
+        match element.data.as_ref() {
+            Some(x) => Ok(Some(serde_json::from_slice(x)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Replaces the payload of an element with a JSON value
+    fn set_json_payload(element: &mut common::SourceElement, payload: &Value) -> Result<(), Box<error::Error>> {
+        element.data = Some(serde_json::to_vec(payload)?.into_boxed_slice());
+        Ok(())
+    }
+
+    /// A condition on the JSON payload of the elements
+    #[derive(Debug, Clone)]
+    pub struct JsonCondition {
+        /// The JSON pointer of the checked field, e.g. `/customer/country`
+        pub path: String,
+        /// The expected field value. When not specified, the field is just expected to exist
+        pub value: Option<Value>
+    }
+
+    impl JsonCondition {
+        /// Returns whether an element matches the condition. Absent and non-JSON payloads do not match.
+        pub fn matches(&self, element: &common::SourceElement) -> bool {
+            let payload = match element.data.as_ref().and_then(|x| serde_json::from_slice::<Value>(x).ok()) {
+                Some(x) => x,
+                None => {
+                    return false;
+                }
+            };
+            match (payload.pointer(&self.path), self.value.as_ref()) {
+                (Some(x), Some(y)) => x == y,
+                (Some(_), None) => true,
+                (None, _) => false
+            }
+        }
+    }
+
+    /// Keeps only the elements matching a condition
+    #[derive(Debug)]
+    pub struct FilterTransform {
+        pub condition: JsonCondition
+    }
+
+    impl Transform for FilterTransform {
+        fn apply(&self, element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            Ok(if self.condition.matches(&element) { vec![element] } else { vec![] })
+        }
+    }
+
+    /// Drops the elements matching a condition
+    #[derive(Debug)]
+    pub struct DropTransform {
+        pub condition: JsonCondition
+    }
+
+    impl Transform for DropTransform {
+        fn apply(&self, element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            Ok(if self.condition.matches(&element) { vec![] } else { vec![element] })
+        }
+    }
+
+    /// Replaces JSON payloads with an object made of some of their fields. Missing fields are `null`.
+    /// Absent payloads, e.g. tombstones, are left as they are.
+    #[derive(Debug)]
+    pub struct MapTransform {
+        /// The fields of the new payload, as name and JSON pointer in the original payload
+        pub fields: Vec<(String, String)>
+    }
+
+    impl Transform for MapTransform {
+        fn apply(&self, mut element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            if let Some(payload) = json_payload(&element)? {
+                let mut mapped = Map::new();
+                for (name, path) in self.fields.iter() {
+                    mapped.insert(name.clone(), payload.pointer(path).cloned().unwrap_or(Value::Null));
+                }
+                set_json_payload(&mut element, &Value::Object(mapped))?;
+            }
+            Ok(vec![element])
+        }
+    }
+
+    /// Adds static headers to the elements and static fields to their JSON object payloads.
+    /// Absent payloads, e.g. tombstones, only get the headers.
+    #[derive(Debug)]
+    pub struct EnrichTransform {
+        /// The added headers, as name and value pairs
+        pub headers: Vec<(String, String)>,
+        /// The added payload fields, as name and value pairs
+        pub fields: Vec<(String, Value)>
+    }
+
+    impl Transform for EnrichTransform {
+        fn apply(&self, mut element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            for (name, value) in self.headers.iter() {
+                element.headers.push((Box::from(name.as_str()), Box::from(value.as_str())));
+            }
+            if self.fields.is_empty() {
+                return Ok(vec![element]);
+            }
+            if let Some(mut payload) = json_payload(&element)? {
+                match payload.as_object_mut() {
+                    Some(x) => {
+                        for (name, value) in self.fields.iter() {
+                            x.insert(name.clone(), value.clone());
+                        }
+                    },
+                    None => {
+                        return Err(Box::from(format!("Payload of element {} is not a JSON object", element.id)));
+                    }
+                }
+                set_json_payload(&mut element, &payload)?;
+            }
+            Ok(vec![element])
+        }
+    }
+
+    /// Splits the elements whose payload has a JSON array into one element per array item,
+    /// having the same key, headers and topic. The id of every item is [derived](fn.derived_id.html)
+    /// from the original id and the item index, e.g. `42#split-0`.
+    /// Elements without such an array are left as they are.
+    #[derive(Debug)]
+    pub struct SplitTransform {
+        /// The JSON pointer of the array, e.g. `/items`. The empty string stands for the whole payload
+        pub path: String
+    }
+
+    impl Transform for SplitTransform {
+        fn apply(&self, element: common::SourceElement) -> Result<Vec<common::SourceElement>, Box<error::Error>> {
+            let items = match json_payload(&element)?.as_ref().and_then(|x| x.pointer(&self.path)).and_then(|x| x.as_array()) {
+                Some(x) => x.clone(),
+                None => {
+                    return Ok(vec![element]);
+                }
+            };
+            let mut result = Vec::new();
+            for (index, item) in items.iter().enumerate() {
+                let mut split = common::SourceElement{
+                    id: derived_id(&element.id, "split", &index.to_string()),
+                    key: element.key.clone(),
+                    data: None,
+                    headers: element.headers.clone(),
+                    topic: element.topic.clone()
+                };
+                set_json_payload(&mut split, item)?;
+                result.push(split);
+            }
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashSet;
+    use std::error;
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use super::pipeline::*;
+    use crate::common::{SourceElement, StreamConsumer};
+    use crate::common::tests::{MemoryStreamConsumer, source_element};
+
+    fn payload(consumer: &MemoryStreamConsumer, index: usize) -> Value {
+        serde_json::from_slice(consumer.elements[index].data.as_ref().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn transforms_are_chained() {
+        let transforms: Vec<Arc<Transform>> = vec![
+            Arc::new(FilterTransform{condition: JsonCondition{path: "/country".to_string(), value: Some(json!("IT"))}}),
+            Arc::new(SplitTransform{path: "/items".to_string()}),
+            Arc::new(DropTransform{condition: JsonCondition{path: "/gift".to_string(), value: None}}),
+            Arc::new(MapTransform{fields: vec![("sku".to_string(), "/code".to_string())]}),
+            Arc::new(EnrichTransform{headers: vec![("origin".to_string(), "orders".to_string())], fields: vec![("version".to_string(), json!(2))]})
+        ];
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        {
+            let mut consumer = TransformStreamConsumer::new(&mut memory_consumer, transforms);
+            consumer.write(source_element("1", r#"{"country": "IT", "items": [{"code": "a"}, {"code": "b", "gift": true}, {"code": "c"}]}"#)).unwrap();
+            consumer.write(source_element("2", r#"{"country": "FR", "items": [{"code": "d"}]}"#)).unwrap();
+            consumer.flush().unwrap();
+        }
+        assert_eq!(2, memory_consumer.elements.len());
+        assert_eq!(1, memory_consumer.flushes);
+        assert_eq!(json!({"sku": "a", "version": 2}), payload(&memory_consumer, 0));
+        assert_eq!(json!({"sku": "c", "version": 2}), payload(&memory_consumer, 1));
+        assert_eq!("1#split-2", &*memory_consumer.elements[1].id);
+        assert_eq!(vec![(Box::from("origin"), Box::from("orders"))], memory_consumer.elements[1].headers);
+    }
+
+    #[test]
+    fn tombstones_are_not_reshaped() {
+        let mut element = source_element("1", "");
+        element.data = None;
+        let result = MapTransform{fields: vec![("sku".to_string(), "/code".to_string())]}.apply(element).unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(None, result[0].data);
+    }
+
+    /// A consumer keeping the first element of every id, as sinks deduplicating by id do
+    #[derive(Default)]
+    struct DeduplicatingStreamConsumer {
+        ids: HashSet<String>,
+        elements: Vec<SourceElement>
+    }
+
+    impl StreamConsumer for DeduplicatingStreamConsumer {
+        fn write(&mut self, element: SourceElement) -> Result<(), Box<error::Error>> {
+            if self.ids.insert(element.id.to_string()) {
+                self.elements.push(element);
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn split_items_survive_deduplication() {
+        let mut dedup_consumer = DeduplicatingStreamConsumer::default();
+        {
+            let transforms: Vec<Arc<Transform>> = vec![Arc::new(SplitTransform{path: "/items".to_string()})];
+            let mut consumer = TransformStreamConsumer::new(&mut dedup_consumer, transforms);
+            consumer.write(source_element("42", r#"{"items": [1, 2, 3]}"#)).unwrap();
+            consumer.write(source_element("42", r#"{"items": [1, 2, 3]}"#)).unwrap();
+            consumer.flush().unwrap();
+        }
+        let ids: Vec<&str> = dedup_consumer.elements.iter().map(|x| &*x.id).collect();
+        assert_eq!(vec!["42#split-0", "42#split-1", "42#split-2"], ids);
+    }
+}
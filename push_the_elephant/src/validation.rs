@@ -0,0 +1,162 @@
+pub mod stream {
+    extern crate jsonschema;
+    use std::collections::HashMap;
+    use std::error;
+    use std::fs;
+    use std::boxed::Box;
+    use jsonschema::JSONSchema;
+    use serde_json::Value;
+    use crate::common;
+    use crate::dead_letter::stream::DeadLetterQueue;
+    use log::{info, debug};
+
+    /// A stream consumer validating JSON payloads against the JSON Schema of their topic before
+    /// passing them to another consumer. Invalid payloads are sent to the dead letter destination,
+    /// with the validation errors attached. Payloads of topics without a schema and absent
+    /// payloads, e.g. tombstones, are passed as they are.
+    pub struct JsonSchemaStreamConsumer<'a, C: common::StreamConsumer> {
+        /// The consumer receiving the valid messages
+        consumer: C,
+        /// The default topic of the messages
+        topic: &'a str,
+        /// The compiled schemas by topic
+        schemas: HashMap<String, JSONSchema>,
+        /// The destination of the invalid payloads
+        dead_letter: DeadLetterQueue<'a>
+    }
+
+    impl <'a, C: common::StreamConsumer> JsonSchemaStreamConsumer<'a, C> {
+        /// Returns a new JSON Schema Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * consumer - the consumer receiving the valid messages
+        /// * topic - the topic of the messages that do not specify their own
+        /// * schema_filenames - the JSON Schema files by topic
+        /// * dead_letter - the destination of the invalid payloads
+        pub fn new(consumer: C, topic: &'a str, schema_filenames: &HashMap<String, String>, dead_letter: DeadLetterQueue<'a>) -> Result<JsonSchemaStreamConsumer<'a, C>, Box<error::Error>> {
+            let mut schemas = HashMap::new();
+            for (schema_topic, filename) in schema_filenames.iter() {
+                info!(target: "validation", "Using JSON Schema {:?} for topic {:?}", filename, schema_topic);
+                let schema: Value = serde_json::from_str(&fs::read_to_string(filename)?)?;
+                let compiled = JSONSchema::compile(&schema)
+                    .map_err(|e| format!("Invalid JSON Schema {}: {}", filename, e))?;
+                schemas.insert(schema_topic.clone(), compiled);
+            }
+            Ok(JsonSchemaStreamConsumer{consumer, topic, schemas, dead_letter})
+        }
+    }
+
+    /// Validates a JSON payload, returning the validation errors joined in a single error
+    pub fn validate_json(schema: &JSONSchema, payload: &[u8]) -> Result<(), Box<error::Error>> {
+        let instance: Value = serde_json::from_slice(payload)?;
+        let result = schema.validate(&instance);
+        if let Err(errors) = result {
+            let messages: Vec<String> = errors.map(|e| format!("{}: {}", e.instance_path, e)).collect();
+            return Err(Box::from(messages.join("; ")));
+        }
+        Ok(())
+    }
+
+    impl <'a, C: common::StreamConsumer> common::StreamConsumer for JsonSchemaStreamConsumer<'a, C> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            let topic = element.topic.as_ref().map_or(self.topic, |x| &**x);
+            let validated = match (self.schemas.get(topic), element.data.as_ref()) {
+                (Some(schema), Some(data)) => validate_json(schema, data),
+                _ => {
+                    return self.consumer.write(element);
+                }
+            };
+            match validated {
+                Ok(_) => {
+                    debug!(target: "validation", "Validated element {:?}", element.id);
+                    self.consumer.write(element)
+                },
+                Err(e) => self.dead_letter.reject(element, e)
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            self.dead_letter.flush()?;
+            self.consumer.flush()
+        }
+
+        fn discard(&mut self) {
+            self.consumer.discard();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use super::stream::JsonSchemaStreamConsumer;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{MemoryStreamConsumer, source_element};
+    use crate::dead_letter::stream::{DeadLetterQueue, DEAD_LETTER_ERROR_HEADER};
+
+    fn schema_filenames() -> HashMap<String, String> {
+        let filename = env::temp_dir().join(format!("push_the_elephant_test_event_{}.schema.json", rand::random::<u64>()));
+        fs::write(&filename, r#"{"type": "object", "properties": {"count": {"type": "integer"}}, "required": ["count"]}"#).unwrap();
+        let mut result = HashMap::new();
+        result.insert("events".to_string(), filename.to_str().unwrap().to_string());
+        result
+    }
+
+    #[test]
+    fn invalid_payloads_are_sent_to_the_dead_letter_destination() {
+        let mut memory_consumer = MemoryStreamConsumer::default();
+        let mut dead_letter_consumer = MemoryStreamConsumer::default();
+        {
+            let mut consumer = JsonSchemaStreamConsumer::new(
+                &mut memory_consumer,
+                "events",
+                &schema_filenames(),
+                DeadLetterQueue::new(Some(Box::new(&mut dead_letter_consumer)))
+            ).unwrap();
+            consumer.write(source_element("1", r#"{"count": 1}"#)).unwrap();
+            consumer.write(source_element("2", r#"{"count": "one"}"#)).unwrap();
+            let mut other_topic = source_element("3", r#"{"count": "one"}"#);
+            other_topic.topic = Some(Box::from("others"));
+            consumer.write(other_topic).unwrap();
+            consumer.flush().unwrap();
+        }
+        assert_eq!(2, memory_consumer.elements.len());
+        assert_eq!("1", &*memory_consumer.elements[0].id);
+        assert_eq!("3", &*memory_consumer.elements[1].id);
+        assert_eq!(1, dead_letter_consumer.elements.len());
+        assert_eq!(1, dead_letter_consumer.flushes);
+        assert_eq!("2", &*dead_letter_consumer.elements[0].id);
+        assert_eq!(&*dead_letter_consumer.elements[0].headers[0].0, DEAD_LETTER_ERROR_HEADER);
+        assert!(dead_letter_consumer.elements[0].headers[0].1.starts_with("/count: "));
+    }
+
+    #[test]
+    fn rejected_payloads_keep_their_topic_unless_reset() {
+        let mut dead_letter_consumer = MemoryStreamConsumer::default();
+        let mut kafka_dead_letter_consumer = MemoryStreamConsumer::default();
+        for dead_letter in vec![
+            DeadLetterQueue::new(Some(Box::new(&mut dead_letter_consumer))),
+            DeadLetterQueue::with_default_topic(Box::new(&mut kafka_dead_letter_consumer))
+        ].into_iter() {
+            let mut consumer = JsonSchemaStreamConsumer::new(
+                MemoryStreamConsumer::default(), "others", &schema_filenames(), dead_letter
+            ).unwrap();
+            let mut element = source_element("1", r#"{}"#);
+            element.topic = Some(Box::from("events"));
+            consumer.write(element).unwrap();
+        }
+        assert_eq!(Some(Box::from("events")), dead_letter_consumer.elements[0].topic);
+        assert_eq!(None, kafka_dead_letter_consumer.elements[0].topic);
+    }
+
+    #[test]
+    fn invalid_payloads_fail_without_dead_letter_destination() {
+        let mut consumer = JsonSchemaStreamConsumer::new(
+            MemoryStreamConsumer::default(), "events", &schema_filenames(), DeadLetterQueue::new(None)
+        ).unwrap();
+        assert!(consumer.write(source_element("1", r#"{}"#)).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+pub mod stream {
+    extern crate hmac;
+    extern crate sha2;
+    extern crate ureq;
+    extern crate base64;
+    use std::error;
+    use std::fmt;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+    use std::boxed::Box;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use serde_json::{json, Map, Value};
+    use crate::common;
+    use log::{info, debug, warn};
+
+    /// The header carrying the HMAC-SHA256 signature of the request body
+    pub const SIGNATURE_HEADER: &str = "X-PTE-Signature";
+
+    /// The header carrying the message id, when messages are sent one per request
+    pub const MESSAGE_ID_HEADER: &str = "X-PTE-Id";
+
+    /// The header carrying the message key, when messages are sent one per request
+    pub const MESSAGE_KEY_HEADER: &str = "X-PTE-Key";
+
+    /// The delay before the first retry, doubled at every further retry, unless the server sends `Retry-After`
+    const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+    /// The longest delay between retries
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+    /// How messages are sent to the webhook
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum WebhookBatching {
+        /// One request per message, whose body is the payload. The message id, key and headers are
+        /// sent as request headers
+        Single,
+        /// One request per flush and URL, whose body is a JSON array of objects having the `id`,
+        /// `key`, `headers` and `payload` fields. Payloads that are not JSON are sent as base64,
+        /// flagged by a `"payload_encoding": "base64"` field
+        Array
+    }
+
+    #[derive(Debug)]
+    pub struct WebhookBatchingError {
+        batching: String
+    }
+
+    impl error::Error for WebhookBatchingError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            None
+        }
+    }
+
+    impl fmt::Display for WebhookBatchingError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "invalid webhook batching {:?}", self.batching)
+        }
+    }
+
+    impl FromStr for WebhookBatching {
+        type Err = WebhookBatchingError;
+
+        fn from_str(s: &str) -> Result<WebhookBatching, WebhookBatchingError> {
+            match s {
+                "single" => Ok(WebhookBatching::Single),
+                "array" => Ok(WebhookBatching::Array),
+                _ => Err(WebhookBatchingError{batching: s.to_string()})
+            }
+        }
+    }
+
+    /// A stream consumer POSTing messages to HTTP endpoints. Requests failing with a 5xx or 429
+    /// status, or without response, are retried, waiting as requested by the `Retry-After`
+    /// header when present. Buffered messages are sent on flush.
+    pub struct WebhookStreamConsumer<'a> {
+        agent: ureq::Agent,
+        url: &'a str,
+        headers: &'a [(String, String)],
+        secret: Option<&'a str>,
+        batching: WebhookBatching,
+        max_retries: u32,
+        buffer: Vec<common::SourceElement>,
+        max_buffer_size: usize
+    }
+
+    impl <'a> WebhookStreamConsumer<'a> {
+        /// Returns a new Webhook Stream Consumer
+        ///
+        /// # Arguments
+        ///
+        /// * url - the URL template, where `{topic}`, `{key}` and `{id}` are replaced by the
+        /// message topic (empty when not set), key (the id when not set) and id
+        /// * headers - the headers of every request
+        /// * secret - the key the request bodies are signed with, in the
+        /// [SIGNATURE_HEADER](constant.SIGNATURE_HEADER.html) header, if any
+        /// * batching - how messages are sent
+        /// * timeout - the timeout of every request
+        /// * max_retries - how many times a failed request is retried
+        /// * max_buffer_size - the number of messages after which they are sent
+        pub fn new(url: &'a str, headers: &'a [(String, String)], secret: Option<&'a str>, batching: WebhookBatching, timeout: Duration, max_retries: u32, max_buffer_size: usize) -> WebhookStreamConsumer<'a> {
+            info!(target: "webhook", "Creating webhook consumer for {:?}, batching {:?}", url, batching);
+            let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+            WebhookStreamConsumer{agent, url, headers, secret, batching, max_retries, buffer: Vec::new(), max_buffer_size}
+        }
+
+        /// Sends a request, retrying it on 5xx and 429 statuses and on transport failures
+        fn post(&self, url: &str, message_headers: &[(&str, &str)], content_type: &str, body: &[u8]) -> Result<(), Box<error::Error>> {
+            let signature = self.secret.map(|x| webhook_signature(x, body));
+            let headers = request_headers(self.headers, message_headers, content_type, signature.as_ref().map(|x| x.as_str()));
+            let mut retries = 0;
+            loop {
+                let mut request = self.agent.post(url);
+                for (name, value) in headers.iter() {
+                    request = request.set(name, value);
+                }
+                let delay = match request.send_bytes(body) {
+                    Ok(_) => {
+                        return Ok(());
+                    },
+                    Err(ureq::Error::Status(status, response)) if status >= 500 || status == 429 => {
+                        warn!(target: "webhook", "Request to {} failed with status {}", url, status);
+                        response.header("Retry-After").and_then(retry_after)
+                    },
+                    Err(ureq::Error::Transport(e)) => {
+                        warn!(target: "webhook", "Request to {} failed: {}", url, e);
+                        None
+                    },
+                    Err(e) => {
+                        return Err(Box::new(e));
+                    }
+                };
+                if retries >= self.max_retries {
+                    return Err(Box::from(format!("Request to {} failed {} times", url, retries + 1)));
+                }
+                let delay = delay.unwrap_or_else(|| RETRY_BACKOFF * 2u32.pow(retries.min(16))).min(MAX_RETRY_DELAY);
+                debug!(target: "webhook", "Retrying request to {} in {:?}", url, delay);
+                thread::sleep(delay);
+                retries += 1;
+            }
+        }
+    }
+
+    /// Returns the hex HMAC-SHA256 signature of a body, prefixed by `sha256=`
+    pub fn webhook_signature(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(body);
+        let digest: String = mac.finalize().into_bytes().iter().map(|x| format!("{:02x}", x)).collect();
+        format!("sha256={}", digest)
+    }
+
+    /// Returns the delay requested by a `Retry-After` header, either in seconds or as an HTTP date
+    pub(crate) fn retry_after(value: &str) -> Option<Duration> {
+        if let Ok(x) = value.trim().parse::<u64>() {
+            return Some(Duration::from_secs(x));
+        }
+        let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+        Some((date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or(Duration::from_secs(0)))
+    }
+
+    /// Returns the headers of a request as name and value pairs. The content type, the configured
+    /// headers and the signature come last and replace any message header of the same name, so
+    /// that rows cannot override them, e.g. `Authorization`
+    pub(crate) fn request_headers<'b>(configured: &'b [(String, String)], message_headers: &[(&'b str, &'b str)], content_type: &'b str, signature: Option<&'b str>) -> Vec<(&'b str, &'b str)> {
+        let mut reserved: Vec<(&str, &str)> = vec![("Content-Type", content_type)];
+        reserved.extend(configured.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        if let Some(x) = signature {
+            reserved.push((SIGNATURE_HEADER, x));
+        }
+        let mut headers: Vec<(&str, &str)> = message_headers.iter()
+            .filter(|(name, _)| !reserved.iter().any(|(x, _)| x.eq_ignore_ascii_case(name)))
+            .cloned()
+            .collect();
+        headers.extend(reserved);
+        headers
+    }
+
+    /// Returns the URL of a message, whose topic, key and id are percent-encoded
+    pub(crate) fn url(template: &str, element: &common::SourceElement) -> String {
+        template
+            .replace("{topic}", &common::percent_encode(element.topic.as_ref().map_or("", |x| &**x)))
+            .replace("{key}", &common::percent_encode(element.key.as_ref().map_or(&*element.id, |x| &**x)))
+            .replace("{id}", &common::percent_encode(&element.id))
+    }
+
+    /// Returns the JSON object representing a message in the array batches. Payloads that are not
+    /// JSON are encoded as base64, so that binary ones reach the webhook unchanged
+    pub(crate) fn envelope(element: &common::SourceElement) -> Value {
+        let mut headers = Map::new();
+        for (name, value) in element.headers.iter() {
+            headers.insert(name.to_string(), Value::String(value.to_string()));
+        }
+        let mut envelope = json!({"id": &*element.id, "key": element.key.as_ref().map(|x| &**x), "headers": headers, "payload": null});
+        if let Some(x) = element.data.as_ref() {
+            let payload = match serde_json::from_slice(x) {
+                Ok(payload) => payload,
+                Err(_) => {
+                    envelope["payload_encoding"] = Value::String("base64".to_string());
+                    Value::String(base64::encode(x))
+                }
+            };
+            envelope["payload"] = payload;
+        }
+        envelope
+    }
+
+    impl <'a> common::StreamConsumer for WebhookStreamConsumer<'a> {
+        fn write(&mut self, element: common::SourceElement) -> Result<(), Box<error::Error>> {
+            self.buffer.push(element);
+            if self.buffer.len() >= self.max_buffer_size {
+                return self.flush();
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Box<error::Error>> {
+            match self.batching {
+                WebhookBatching::Single => {
+                    for element in self.buffer.iter() {
+                        let mut message_headers: Vec<(&str, &str)> = element.headers.iter()
+                            .filter(|(name, _)| !name.eq_ignore_ascii_case(MESSAGE_ID_HEADER) && !name.eq_ignore_ascii_case(MESSAGE_KEY_HEADER))
+                            .map(|(name, value)| (&**name, &**value))
+                            .collect();
+                        message_headers.push((MESSAGE_ID_HEADER, &element.id));
+                        if let Some(x) = element.key.as_ref() {
+                            message_headers.push((MESSAGE_KEY_HEADER, x));
+                        }
+                        let data: &[u8] = element.data.as_ref().map_or(&[][..], |x| &**x);
+                        let content_type = if serde_json::from_slice::<Value>(data).is_ok() { "application/json" } else { "application/octet-stream" };
+                        self.post(&url(self.url, element), &message_headers, content_type, data)?;
+                    }
+                },
+                WebhookBatching::Array => {
+                    let mut batches: Vec<(String, Vec<Value>)> = Vec::new();
+                    for element in self.buffer.iter() {
+                        let element_url = url(self.url, element);
+                        match batches.iter_mut().find(|x| x.0 == element_url) {
+                            Some(x) => x.1.push(envelope(element)),
+                            None => batches.push((element_url, vec![envelope(element)]))
+                        }
+                    }
+                    for (batch_url, batch) in batches.iter() {
+                        self.post(batch_url, &[], "application/json", &serde_json::to_vec(batch)?)?;
+                    }
+                }
+            }
+            debug!(target: "webhook", "Sent {} elements", self.buffer.len());
+            self.buffer.clear();
+            Ok(())
+        }
+
+        fn discard(&mut self) {
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+    use super::stream::*;
+    use serde_json::json;
+    use crate::common::StreamConsumer;
+    use crate::common::tests::{source_element, http_stand_in};
+
+    #[test]
+    fn messages_are_posted_one_per_request() {
+        let (url, requests) = http_stand_in(vec![(200, ""), (429, ""), (200, "")]);
+        let template = format!("{}/hooks/{{key}}", url);
+        let mut consumer = WebhookStreamConsumer::new(&template, &[], Some("secret"), WebhookBatching::Single, Duration::from_secs(5), 3, 100);
+        consumer.write(source_element("1", r#"{"a": 1}"#)).unwrap();
+        consumer.write(source_element("2", r#"{"a": 2}"#)).unwrap();
+        consumer.flush().unwrap();
+        assert_eq!(
+            vec![
+                ("POST /hooks/1 HTTP/1.1".to_string(), r#"{"a": 1}"#.to_string()),
+                ("POST /hooks/2 HTTP/1.1".to_string(), r#"{"a": 2}"#.to_string()),
+                ("POST /hooks/2 HTTP/1.1".to_string(), r#"{"a": 2}"#.to_string())
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn messages_are_posted_as_arrays() {
+        let (url, requests) = http_stand_in(vec![(200, "")]);
+        let mut consumer = WebhookStreamConsumer::new(&url, &[], None, WebhookBatching::Array, Duration::from_secs(5), 0, 100);
+        consumer.write(source_element("1", r#"{"a": 1}"#)).unwrap();
+        consumer.write(source_element("2", "text")).unwrap();
+        consumer.flush().unwrap();
+        assert_eq!(
+            vec![(
+                "POST / HTTP/1.1".to_string(),
+                r#"[{"headers":{},"id":"1","key":null,"payload":{"a":1}},{"headers":{},"id":"2","key":null,"payload":"dGV4dA==","payload_encoding":"base64"}]"#.to_string()
+            )],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn binary_payloads_are_sent_as_base64() {
+        let mut element = source_element("1", "");
+        element.data = Some(Box::from(&[0xff, 0x00, 0xfe][..]));
+        let binary = envelope(&element);
+        assert_eq!(json!("/wD+"), binary["payload"]);
+        assert_eq!(json!("base64"), binary["payload_encoding"]);
+        element.data = None;
+        let tombstone = envelope(&element);
+        assert_eq!(json!(null), tombstone["payload"]);
+        assert_eq!(None, tombstone.get("payload_encoding"));
+    }
+
+    #[test]
+    fn message_headers_cannot_override_the_configured_ones() {
+        let configured = vec![("Authorization".to_string(), "Bearer a_token".to_string())];
+        let message_headers = vec![("authorization", "Bearer forged"), ("content-type", "text/html"), ("X-PTE-Signature", "forged"), ("type", "created")];
+        assert_eq!(
+            vec![("type", "created"), ("Content-Type", "application/json"), ("Authorization", "Bearer a_token"), (SIGNATURE_HEADER, "sha256=a")],
+            request_headers(&configured, &message_headers, "application/json", Some("sha256=a"))
+        );
+    }
+
+    #[test]
+    fn url_substitutions_are_percent_encoded() {
+        let mut element = source_element("1", "a");
+        element.key = Some(Box::from("a/b c?d"));
+        element.topic = Some(Box::from("orders&x=1"));
+        assert_eq!(
+            "https://example.com/orders%26x%3D1/a%2Fb%20c%3Fd?id=1",
+            url("https://example.com/{topic}/{key}?id={id}", &element)
+        );
+    }
+
+    #[test]
+    fn bodies_are_signed() {
+        assert_eq!(
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+            webhook_signature("key", b"The quick brown fox jumps over the lazy dog")
+        );
+    }
+}
@@ -1,15 +1,26 @@
 use crate::kafka;
 use crate::common::*;
 use crate::pgsql;
+use crate::avro;
+use crate::protobuf;
+use crate::dead_letter;
+use crate::cloud_events;
+use crate::validation;
+use crate::quarantine;
+use crate::transform;
+use crate::sink;
 use std::time::Duration;
 use std::error;
 use std::thread;
 use std::fmt;
 use crate::conf::configuration;
+use crate::schema::outbox;
 use std::sync::Arc;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 #[derive(Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct Worker<'a> {
     #[builder(default = "\"postgres://postgres@localhost:5433\"")]
     pgurl: &'a str,
@@ -17,10 +28,20 @@ pub struct Worker<'a> {
     table_name: &'a str,
     #[builder(default = "\"payload\"")]
     column_name: &'a str,
+    #[builder(default = "pgsql::stream::PayloadMode::Column")]
+    payload_mode: pgsql::stream::PayloadMode,
+    #[builder(default = "None", setter(into))]
+    key_column_name: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    headers_column_name: Option<&'a str>,
+    #[builder(default = "pgsql::stream::OutboxFormat::Plain")]
+    outbox_format: pgsql::stream::OutboxFormat,
     #[builder(default = "\"events.activity\"")]
     channel: &'a str,
     #[builder(default = "\"events\"")]
     topic_name: &'a str,
+    #[builder(default = "None", setter(into))]
+    sink: Option<sink::configuration::SinkConfiguration>,
     #[builder(default = "100 as usize")]
     buffer_size: usize,
     #[builder(default = "vec![\"localhost:9092\".to_string()]")]
@@ -28,7 +49,33 @@ pub struct Worker<'a> {
     #[builder(default = "Duration::from_secs(3)")]
     notify_timeout: Duration,
     #[builder(default = "Duration::from_secs(60)")]
-    notify_timeout_total: Duration
+    notify_timeout_total: Duration,
+    #[builder(default = "None", setter(into))]
+    schema_registry_url: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    avro_schema_file: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    protobuf_descriptor_set: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    protobuf_message: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    dead_letter_topic: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    quarantine_table: Option<&'a str>,
+    #[builder(default = "HashMap::new()")]
+    json_schemas: HashMap<String, String>,
+    #[builder(default = "Vec::new()")]
+    transforms: Vec<Arc<transform::pipeline::Transform>>,
+    #[builder(default = "None", setter(into))]
+    cloud_events_mode: Option<cloud_events::format::CloudEventsMode>,
+    #[builder(default = "\"id\"")]
+    cloud_events_id_column: &'a str,
+    #[builder(default = "None", setter(into))]
+    cloud_events_source_column: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    cloud_events_type_column: Option<&'a str>,
+    #[builder(default = "None", setter(into))]
+    cloud_events_time_column: Option<&'a str>
 }
 
 #[derive(Debug, Clone)]
@@ -46,14 +93,217 @@ impl error::Error for ThreadDiedError {
     }
 }
 
+impl <'a> WorkerBuilder<'a> {
+    /// Returns a [WorkerBuilder](struct.WorkerBuilder.html) initialized from a
+    /// [PushTheElephantConfiguration](struct.PushTheElephantConfiguration.html)
+    pub fn from_configuration(c: &'a configuration::PushTheElephantConfiguration) -> WorkerBuilder<'a> {
+        let mut builder = WorkerBuilder::default();
+        if let Some(x) = c.pgurl.as_ref() {
+            builder.pgurl(x);
+        }
+        if let Some(x) = c.table_name.as_ref() {
+            builder.table_name(x);
+        }
+        if let Some(x) = c.column_name.as_ref() {
+            builder.column_name(x);
+        }
+        if let Some(x) = c.payload_mode.as_ref() {
+            builder.payload_mode(x.clone());
+        }
+        if let Some(x) = c.key_column_name.as_ref() {
+            builder.key_column_name(x.as_str());
+        }
+        if let Some(x) = c.headers_column_name.as_ref() {
+            builder.headers_column_name(x.as_str());
+        }
+        if let Some(x) = c.outbox_format.as_ref() {
+            builder.outbox_format(x.clone());
+        }
+        if let Some(x) = c.channel.as_ref() {
+            builder.channel(x);
+        }
+        if let Some(x) = c.topic_name.as_ref() {
+            builder.topic_name(x);
+        }
+        if let Some(x) = c.sink.as_ref() {
+            builder.sink(x.clone());
+        }
+        if let Some(x) = c.buffer_size {
+            builder.buffer_size(x);
+        }
+        if let Some(x) = &c.kafka_brokers {
+            builder.kafka_brokers(x.to_vec());
+        }
+        if let Some(x) = c.notify_timeout {
+            builder.notify_timeout(x);
+        }
+        if let Some(x) = c.notify_timeout {
+            builder.notify_timeout_total(x);
+        }
+        if let Some(x) = c.schema_registry_url.as_ref() {
+            builder.schema_registry_url(x.as_str());
+        }
+        if let Some(x) = c.avro_schema_file.as_ref() {
+            builder.avro_schema_file(x.as_str());
+        }
+        if let Some(x) = c.protobuf_descriptor_set.as_ref() {
+            builder.protobuf_descriptor_set(x.as_str());
+        }
+        if let Some(x) = c.protobuf_message.as_ref() {
+            builder.protobuf_message(x.as_str());
+        }
+        if let Some(x) = c.dead_letter_topic.as_ref() {
+            builder.dead_letter_topic(x.as_str());
+        }
+        if let Some(x) = c.quarantine_table.as_ref() {
+            builder.quarantine_table(x.as_str());
+        }
+        if let Some(x) = c.json_schemas.as_ref() {
+            builder.json_schemas(x.clone());
+        }
+        if let Some(x) = c.transforms.as_ref() {
+            builder.transforms(x.clone());
+        }
+        if let Some(x) = c.cloud_events_mode.as_ref() {
+            builder.cloud_events_mode(x.clone());
+        }
+        if let Some(x) = c.cloud_events_id_column.as_ref() {
+            builder.cloud_events_id_column(x);
+        }
+        if let Some(x) = c.cloud_events_source_column.as_ref() {
+            builder.cloud_events_source_column(x.as_str());
+        }
+        if let Some(x) = c.cloud_events_type_column.as_ref() {
+            builder.cloud_events_type_column(x.as_str());
+        }
+        if let Some(x) = c.cloud_events_time_column.as_ref() {
+            builder.cloud_events_time_column(x.as_str());
+        }
+        builder
+    }
+
+    /// Rejects the settings that can not be combined, e.g. Avro and protobuf encodings, or a dead
+    /// letter topic without Kafka brokers
+    fn validate(&self) -> Result<(), String> {
+        let schema_registry = self.schema_registry_url.map_or(false, |x| x.is_some());
+        let avro_schema_file = self.avro_schema_file.map_or(false, |x| x.is_some());
+        let protobuf = self.protobuf_descriptor_set.map_or(false, |x| x.is_some());
+        if (schema_registry || avro_schema_file) && protobuf {
+            return Err("Messages can not be encoded with both Avro and protobuf".to_string());
+        }
+        if avro_schema_file && !schema_registry {
+            return Err("An Avro schema file requires a schema registry".to_string());
+        }
+        let dead_letter_topic = self.dead_letter_topic.map_or(false, |x| x.is_some());
+        let quarantine_table = self.quarantine_table.map_or(false, |x| x.is_some());
+        let kafka = self.sink.as_ref().map_or(true, |x| x.as_ref().map_or(true, |x| x.kafka_brokers().is_some()));
+        if dead_letter_topic && !quarantine_table && !kafka {
+            return Err("A dead letter topic requires a Kafka sink".to_string());
+        }
+        Ok(())
+    }
+
+    /// Appends a transform to the ones applied to the messages before they are sent
+    pub fn transform(&mut self, transform: Arc<transform::pipeline::Transform>) -> &mut Self {
+        self.transforms.get_or_insert_with(Vec::new).push(transform);
+        self
+    }
+}
+
 impl <'a> Worker<'a> {
     pub fn run(&self) -> Result<(), Box<error::Error>> {
-        let mut consumer = kafka::stream::KafkaStreamConsumer::new(self.kafka_brokers.clone(), self.topic_name, self.buffer_size)?;
-        let producer = pgsql::stream::PostgreSQLListenStreamProducer::new(self.pgurl, self.table_name, self.column_name, self.channel, self.notify_timeout_total, self.notify_timeout);
+        let mut consumer : Box<StreamConsumer + '_> = match self.sink.as_ref() {
+            Some(x) => x.consumer(self.table_name)?,
+            None => Box::new(kafka::stream::KafkaStreamConsumer::new(self.kafka_brokers.clone(), self.topic_name, self.buffer_size)?)
+        };
+        if let Some(cloud_events::format::CloudEventsMode::Structured) = self.cloud_events_mode {
+            consumer = Box::new(cloud_events::format::CloudEventsStreamConsumer::new(consumer));
+        }
+        if let Some(x) = self.schema_registry_url {
+            consumer = Box::new(avro::stream::AvroStreamConsumer::new(consumer, x, self.topic(), self.avro_schema_file)?);
+        }
+        let protobuf = self.protobuf_descriptor_set.is_some() && self.protobuf_message.is_some();
+        let dead_letter = if protobuf || !self.json_schemas.is_empty() {
+            self.dead_letter_queue()?
+        } else {
+            dead_letter::stream::DeadLetterQueue::new(None)
+        };
+        if let (Some(x), Some(y)) = (self.protobuf_descriptor_set, self.protobuf_message) {
+            consumer = Box::new(protobuf::stream::ProtobufStreamConsumer::new(consumer, x, y, dead_letter.clone())?);
+        }
+        if !self.transforms.is_empty() {
+            consumer = Box::new(transform::pipeline::TransformStreamConsumer::new(consumer, self.transforms.clone()));
+        }
+        // Payloads are validated as written in the outbox table, before any transform changes them
+        if !self.json_schemas.is_empty() {
+            consumer = Box::new(validation::stream::JsonSchemaStreamConsumer::new(consumer, self.topic(), &self.json_schemas, dead_letter)?);
+        }
+        let producer = pgsql::stream::PostgreSQLListenStreamProducer::new(self.pgurl, self.table_name, self.column_name, self.payload_mode.clone(), self.key_column_name, self.headers_column_name, self.outbox_format.clone(), self.cloud_events_columns(), self.channel, self.notify_timeout_total, self.notify_timeout);
         producer.produce(&mut consumer)?;
         return Ok(());
     }
 
+    /// Returns the topic of the messages without topic, i.e. the one of the sink when it has
+    /// one, otherwise `topic_name`
+    fn topic(&self) -> &str {
+        self.sink.as_ref().and_then(|x| x.topic()).unwrap_or(self.topic_name)
+    }
+
+    /// Returns the columns of the CloudEvents attributes, if messages are wrapped as CloudEvents
+    fn cloud_events_columns(&self) -> Option<cloud_events::format::CloudEventsColumns<'a>> {
+        self.cloud_events_mode.as_ref()?;
+        Some(cloud_events::format::CloudEventsColumns{
+            id_column: self.cloud_events_id_column,
+            source_column: self.cloud_events_source_column,
+            type_column: self.cloud_events_type_column,
+            time_column: self.cloud_events_time_column
+        })
+    }
+
+    /// Returns the destination of the messages that can not be sent, i.e. the quarantine table
+    /// or the dead letter topic, if any
+    fn dead_letter_queue(&self) -> Result<dead_letter::stream::DeadLetterQueue<'a>, Box<error::Error>> {
+        Ok(match (self.quarantine_table, self.dead_letter_topic, self.kafka_brokers()) {
+            (Some(x), _, _) => dead_letter::stream::DeadLetterQueue::new(
+                Some(Box::new(quarantine::stream::PostgreSQLQuarantineStreamConsumer::new(self.pgurl, x, self.table_name)))
+            ),
+            (None, Some(x), Some(y)) => dead_letter::stream::DeadLetterQueue::with_default_topic(
+                Box::new(kafka::stream::KafkaStreamConsumer::new(y.clone(), x, self.buffer_size)?)
+            ),
+            (None, _, _) => dead_letter::stream::DeadLetterQueue::new(None)
+        })
+    }
+
+    /// Returns the Kafka brokers of the sink, i.e. `kafka_brokers` when there is no sink
+    fn kafka_brokers(&self) -> Option<&Vec<String>> {
+        match self.sink.as_ref() {
+            Some(x) => x.kafka_brokers(),
+            None => Some(&self.kafka_brokers)
+        }
+    }
+
+    /// Creates the outbox table of this worker, its notification function and trigger, unless
+    /// they already exist. Returns the schema version of the outbox table.
+    pub fn init_schema(&self) -> Result<i32, Box<error::Error>> {
+        self.outbox_schema().init(self.pgurl)
+    }
+
+    /// Upgrades the outbox table of this worker to the latest schema version.
+    /// Returns the schema version of the outbox table.
+    pub fn migrate_schema(&self) -> Result<i32, Box<error::Error>> {
+        self.outbox_schema().migrate(self.pgurl)
+    }
+
+    fn outbox_schema(&self) -> outbox::OutboxSchema {
+        outbox::OutboxSchema{
+            table_name: self.table_name,
+            column_name: self.column_name,
+            key_column_name: self.key_column_name.unwrap_or("key"),
+            headers_column_name: self.headers_column_name.unwrap_or("headers"),
+            channel: self.channel
+        }
+    }
+
     pub fn multi_run(yaml_configuration_filename: &str) -> Result<(), Box<error::Error>> {
         let threads_control = Arc::new(true);
         let configurations = Arc::new(configuration::PushTheElephantConfiguration::create_from_yaml_filename(yaml_configuration_filename)?);
@@ -64,35 +314,7 @@ impl <'a> Worker<'a> {
              join_handles.push(
                  thread::spawn(
                      move || {
-                         let mut builder = WorkerBuilder::default();
-                         let c = &cur_configurations[i];
-                         if let Some(x) = c.pgurl.as_ref() {
-                             builder.pgurl(x);
-                         }
-                         if let Some(x) = c.table_name.as_ref() {
-                             builder.table_name(x);
-                         }
-                         if let Some(x) = c.column_name.as_ref() {
-                             builder.column_name(x);
-                         }
-                         if let Some(x) = c.channel.as_ref() {
-                             builder.channel(x);
-                         }
-                         if let Some(x) = c.topic_name.as_ref() {
-                             builder.topic_name(x);
-                         }
-                         if let Some(x) = c.buffer_size {
-                             builder.buffer_size(x);
-                         }
-                         if let Some(x) = &c.kafka_brokers {
-                             builder.kafka_brokers(x.to_vec());
-                         }
-                         if let Some(x) = c.notify_timeout {
-                             builder.notify_timeout(x);
-                         }
-                         if let Some(x) = c.notify_timeout {
-                             builder.notify_timeout_total(x);
-                         }
+                         let builder = WorkerBuilder::from_configuration(&cur_configurations[i]);
                          builder.build().unwrap().run().unwrap();
                          println!("{:?}", cur_thread_control);
                      }
@@ -108,3 +330,54 @@ impl <'a> Worker<'a> {
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::WorkerBuilder;
+    use crate::sink::configuration::SinkConfiguration;
+    use crate::redis_streams::stream::RedisFieldMapping;
+
+    #[test]
+    fn avro_and_protobuf_are_exclusive() {
+        let result = WorkerBuilder::default()
+            .schema_registry_url("http://localhost:8081")
+            .protobuf_descriptor_set("events.pb")
+            .protobuf_message("test.Event")
+            .build();
+        assert!(result.is_err());
+        assert!(WorkerBuilder::default().protobuf_descriptor_set("events.pb").protobuf_message("test.Event").build().is_ok());
+    }
+
+    #[test]
+    fn avro_schema_files_require_a_schema_registry() {
+        assert!(WorkerBuilder::default().avro_schema_file("events.avsc").build().is_err());
+        assert!(WorkerBuilder::default().avro_schema_file("events.avsc").schema_registry_url("http://localhost:8081").build().is_ok());
+    }
+
+    #[test]
+    fn topics_are_resolved_from_the_sink() {
+        let worker = WorkerBuilder::default().topic_name("events").build().unwrap();
+        assert_eq!("events", worker.topic());
+        let worker = WorkerBuilder::default()
+            .topic_name("events")
+            .sink(SinkConfiguration::Kafka{brokers: vec![], topic: "orders".to_string(), buffer_size: 1})
+            .build()
+            .unwrap();
+        assert_eq!("orders", worker.topic());
+    }
+
+    #[test]
+    fn dead_letter_topics_require_kafka() {
+        let redis = SinkConfiguration::Redis{url: "redis://localhost".to_string(), stream: "events".to_string(), max_len: None, fields: RedisFieldMapping::default(), buffer_size: 1};
+        assert!(WorkerBuilder::default().dead_letter_topic("rejected").sink(redis.clone()).build().is_err());
+        assert!(WorkerBuilder::default().dead_letter_topic("rejected").quarantine_table("quarantine").sink(redis).build().is_ok());
+        assert!(WorkerBuilder::default().dead_letter_topic("rejected").build().is_ok());
+        let worker = WorkerBuilder::default()
+            .kafka_brokers(vec!["localhost:9092".to_string()])
+            .sink(SinkConfiguration::Kafka{brokers: vec!["kafka:29092".to_string()], topic: "orders".to_string(), buffer_size: 1})
+            .dead_letter_topic("rejected")
+            .build()
+            .unwrap();
+        assert_eq!(Some(&vec!["kafka:29092".to_string()]), worker.kafka_brokers());
+    }
+}
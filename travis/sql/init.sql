@@ -1,6 +1,6 @@
 begin;
 
-create table events(id SERIAL primary key, payload varchar not null);
+create table events(id SERIAL primary key, payload varchar, key varchar);
 ALTER TABLE events OWNER TO push_the_elephant;
 CREATE OR REPLACE FUNCTION tg_notify_events ()
  returns trigger
@@ -10,7 +10,7 @@ declare
   channel text := TG_ARGV[0];
 begin
   PERFORM (
-     with new_row(id, payload) as (select NEW.id, NEW.payload)
+     with new_row(id, payload, key) as (select NEW.id, NEW.payload, NEW.key)
      select pg_notify(channel, row_to_json(new_row)::text)
        from new_row
   );